@@ -6,10 +6,10 @@ use std::io::prelude::*;
 use std::path::Path;
 
 use macroquad::prelude::*;
+use shared::map::WATER_LEVEL_HEIGHT;
 use tiled::LayerType;
 
 const TILE_HEIGHT_OFFSET: f32 = -4.0;
-const WATER_LEVEL_HEIGHT: u32 = 50;
 const MAX_WATER_DISTANCE: f32 = 12.0;
 const SHALLOW_WATER_COLOR: Color = Color::new(0.28, 0.78, 0.9, 1.0);
 const DEEP_WATER_COLOR: Color = Color::new(0.0, 0.16, 0.38, 1.0);
@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::account::{AccountError, AccountRecord, AccountStore};
+
+/// Account store backed by a single JSON file. The whole file is rewritten on every change,
+/// through a temporary file so a crash cannot leave it half written.
+pub struct FileAccountStore {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, AccountRecord>>,
+}
+
+impl FileAccountStore {
+    /// Loads the accounts in `path`, starting empty if the file does not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AccountError> {
+        let path = path.as_ref().to_path_buf();
+        let accounts = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(AccountError::Corrupt)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::default(),
+            Err(e) => return Err(AccountError::Io(e)),
+        };
+        Ok(Self {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    fn save(&self, accounts: &HashMap<String, AccountRecord>) -> Result<(), AccountError> {
+        let contents = serde_json::to_vec_pretty(accounts).map_err(AccountError::Corrupt)?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents).map_err(AccountError::Io)?;
+        fs::rename(&tmp_path, &self.path).map_err(AccountError::Io)
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn get(&self, account: &str) -> Result<Option<AccountRecord>, AccountError> {
+        Ok(self.accounts.lock().unwrap().get(account).cloned())
+    }
+
+    fn create(&self, record: AccountRecord) -> Result<(), AccountError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&record.account) {
+            return Err(AccountError::AlreadyExists);
+        }
+        accounts.insert(record.account.clone(), record.clone());
+        if let Err(e) = self.save(&accounts) {
+            accounts.remove(&record.account);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(account: &str) -> AccountRecord {
+        AccountRecord { account: account.to_string(), password_hash: format!("hash-of-{account}") }
+    }
+
+    #[test]
+    fn persists_accounts() {
+        let path = std::env::temp_dir().join(format!("accounts-{}.json", uuid::Uuid::new_v4()));
+        {
+            let store = FileAccountStore::open(&path).unwrap();
+            store.create(record("graal")).unwrap();
+            store.create(record("merlin")).unwrap();
+            assert!(matches!(store.create(record("graal")), Err(AccountError::AlreadyExists)));
+        }
+
+        let store = FileAccountStore::open(&path).unwrap();
+        assert_eq!(store.get("graal").unwrap(), Some(record("graal")));
+        assert_eq!(store.get("merlin").unwrap(), Some(record("merlin")));
+        assert_eq!(store.get("arthur").unwrap(), None);
+        assert!(matches!(store.create(record("merlin")), Err(AccountError::AlreadyExists)));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::account::{AccountError, AccountRecord, AccountStore};
+
+/// Account store that lives only as long as the process. Meant for tests and local runs.
+#[derive(Default)]
+pub struct InMemoryAccountStore {
+    accounts: Mutex<HashMap<String, AccountRecord>>,
+}
+
+impl AccountStore for InMemoryAccountStore {
+    fn get(&self, account: &str) -> Result<Option<AccountRecord>, AccountError> {
+        Ok(self.accounts.lock().unwrap().get(account).cloned())
+    }
+
+    fn create(&self, record: AccountRecord) -> Result<(), AccountError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&record.account) {
+            return Err(AccountError::AlreadyExists);
+        }
+        accounts.insert(record.account.clone(), record);
+        Ok(())
+    }
+}
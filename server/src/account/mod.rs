@@ -0,0 +1,156 @@
+pub mod file;
+pub mod memory;
+pub mod throttle;
+
+use std::{fmt, io};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+use shared::server_messages::LoginResult;
+
+const ACCOUNT_NAME_LEN: std::ops::RangeInclusive<usize> = 3..=20;
+const PASSWORD_LEN: std::ops::RangeInclusive<usize> = 6..=128;
+/// Checked against when the account does not exist, so that takes as long as a wrong password.
+/// Hashed with the default parameters, like the stored ones.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$fgS3eMuKGaeBjMF0Jya87w$hoL2xafm9iN6aY3Y1hC+GUZfJoAmQWsPC3pyzbZ80ro";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountRecord {
+    pub account: String,
+    /// Argon2 hash in PHC string format, salt included.
+    pub password_hash: String,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    AlreadyExists,
+    Io(io::Error),
+    Corrupt(serde_json::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyExists => write!(f, "account already exists"),
+            Self::Io(err) => write!(f, "account store i/o error: {err}"),
+            Self::Corrupt(err) => write!(f, "account store is corrupt: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+/// Backend holding account credentials. Calls may block on disk, so the nexus only uses it
+/// from blocking tasks.
+pub trait AccountStore: Send + Sync {
+    fn get(&self, account: &str) -> Result<Option<AccountRecord>, AccountError>;
+
+    /// Stores a new account, failing with `AccountError::AlreadyExists` if the name is taken.
+    fn create(&self, record: AccountRecord) -> Result<(), AccountError>;
+}
+
+/// Account names are case-insensitive; this is the form they are stored under.
+pub fn normalize_account(account: &str) -> String {
+    account.trim().to_lowercase()
+}
+
+pub fn register(store: &dyn AccountStore, account: &str, password: &str) -> LoginResult {
+    let account = normalize_account(account);
+    if !ACCOUNT_NAME_LEN.contains(&account.len())
+        || !account.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return LoginResult::InvalidAccountName;
+    }
+    if !PASSWORD_LEN.contains(&password.len()) {
+        return LoginResult::InvalidPassword;
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => hash.to_string(),
+        Err(e) => {
+            error!("Failed to hash password for [{account}]: {e}");
+            return LoginResult::ServerError;
+        }
+    };
+    match store.create(AccountRecord { account: account.clone(), password_hash }) {
+        Ok(()) => {
+            info!("Registered account [{account}]");
+            LoginResult::Success
+        }
+        Err(AccountError::AlreadyExists) => LoginResult::AccountExists,
+        Err(e) => {
+            error!("Failed to store account [{account}]: {e}");
+            LoginResult::ServerError
+        }
+    }
+}
+
+pub fn verify(store: &dyn AccountStore, account: &str, password: &str) -> LoginResult {
+    let account = normalize_account(account);
+    let record = match store.get(&account) {
+        Ok(record) => record,
+        Err(e) => {
+            error!("Failed to load account [{account}]: {e}");
+            return LoginResult::ServerError;
+        }
+    };
+    let Some(record) = record else {
+        if let Ok(hash) = PasswordHash::new(DUMMY_HASH) {
+            let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+        }
+        return LoginResult::InvalidCredentials;
+    };
+    let hash = match PasswordHash::new(&record.password_hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Stored password hash for [{account}] is invalid: {e}");
+            return LoginResult::ServerError;
+        }
+    };
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => LoginResult::Success,
+        Err(_) => LoginResult::InvalidCredentials,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{memory::InMemoryAccountStore, *};
+
+    #[test]
+    fn unknown_accounts_are_hashed_too() {
+        // An unparseable dummy would skip the hashing and make unknown accounts answer fast.
+        assert!(PasswordHash::new(DUMMY_HASH).is_ok());
+    }
+
+    #[test]
+    fn register_then_verify() {
+        let store = InMemoryAccountStore::default();
+        assert_eq!(register(&store, "Graal", "hunter42"), LoginResult::Success);
+        assert_eq!(verify(&store, "graal", "hunter42"), LoginResult::Success);
+        assert_eq!(verify(&store, "graal", "hunter43"), LoginResult::InvalidCredentials);
+        assert_eq!(verify(&store, "nobody", "hunter42"), LoginResult::InvalidCredentials);
+    }
+
+    #[test]
+    fn register_rejects_taken_and_invalid_names() {
+        let store = InMemoryAccountStore::default();
+        assert_eq!(register(&store, "graal", "hunter42"), LoginResult::Success);
+        assert_eq!(register(&store, "GRAAL", "hunter42"), LoginResult::AccountExists);
+        assert_eq!(register(&store, "no spaces", "hunter42"), LoginResult::InvalidAccountName);
+        assert_eq!(register(&store, "graal2", "short"), LoginResult::InvalidPassword);
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        let store = InMemoryAccountStore::default();
+        register(&store, "first", "hunter42");
+        register(&store, "second", "hunter42");
+        let first = store.get("first").unwrap().unwrap();
+        let second = store.get("second").unwrap().unwrap();
+        assert_ne!(first.password_hash, second.password_hash);
+    }
+}
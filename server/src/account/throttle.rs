@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+const MAX_FAILURES: u32 = 5;
+/// Failures allowed from one address across every account it tries.
+const MAX_ADDRESS_FAILURES: u32 = 20;
+const FAILURE_WINDOW: Duration = Duration::from_secs(300);
+const LOCKOUT: Duration = Duration::from_secs(60);
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct Failures {
+    count: u32,
+    first: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn new(now: Instant) -> Self {
+        Self { count: 0, first: now, locked_until: None }
+    }
+
+    /// Counts a failure, locking out once `max` of them fell within the window. Returns whether
+    /// it did.
+    fn record(&mut self, now: Instant, max: u32) -> bool {
+        if now.duration_since(self.first) > FAILURE_WINDOW {
+            self.count = 0;
+            self.first = now;
+        }
+        self.count += 1;
+        if self.count < max {
+            return false;
+        }
+        self.count = 0;
+        self.first = now;
+        self.locked_until = Some(now + LOCKOUT);
+        true
+    }
+
+    fn retry_after(&self, now: Instant) -> Option<Duration> {
+        self.locked_until?.checked_duration_since(now).filter(|left| !left.is_zero())
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.first) > FAILURE_WINDOW && self.retry_after(now).is_none()
+    }
+}
+
+/// Locks an account out for a while after too many failed logins in a short window. Failures are
+/// counted per address, so guessing from one address does not lock the owner out of theirs. An
+/// address guessing at many accounts is locked out of all of them once it used up its own budget.
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: HashMap<(IpAddr, String), Failures>,
+    addresses: HashMap<IpAddr, Failures>,
+}
+
+impl LoginThrottle {
+    /// Time left before `account` may try again from `ip`, if it is locked out.
+    pub fn retry_after(&self, ip: IpAddr, account: &str, now: Instant) -> Option<Duration> {
+        let account = self.failures.get(&(ip, account.to_string())).and_then(|failures| failures.retry_after(now));
+        let address = self.addresses.get(&ip).and_then(|failures| failures.retry_after(now));
+        account.max(address)
+    }
+
+    pub fn record_failure(&mut self, ip: IpAddr, account: &str, now: Instant) {
+        if self.failures.len() >= PRUNE_THRESHOLD || self.addresses.len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        let failures = self.failures.entry((ip, account.to_string())).or_insert_with(|| Failures::new(now));
+        if failures.record(now, MAX_FAILURES) {
+            warn!("Locking out [{account}] from [{ip}] after [{MAX_FAILURES}] failed logins");
+        }
+        let failures = self.addresses.entry(ip).or_insert_with(|| Failures::new(now));
+        if failures.record(now, MAX_ADDRESS_FAILURES) {
+            warn!("Locking out [{ip}] after [{MAX_ADDRESS_FAILURES}] failed logins");
+        }
+    }
+
+    /// Clears the failures of `account` from `ip`. The address keeps its own, or logging into an
+    /// account of its own would reset its budget.
+    pub fn record_success(&mut self, ip: IpAddr, account: &str) {
+        self.failures.remove(&(ip, account.to_string()));
+    }
+
+    /// Forgets accounts and addresses whose failures expired, so guessing many names cannot grow
+    /// the maps forever.
+    fn prune(&mut self, now: Instant) {
+        self.failures.retain(|_, failures| !failures.expired(now));
+        self.addresses.retain(|_, failures| !failures.expired(now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locks_out_after_repeated_failures() {
+        let mut throttle = LoginThrottle::default();
+        let now = Instant::now();
+        let (attacker, owner): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        for _ in 0..MAX_FAILURES - 1 {
+            throttle.record_failure(attacker, "graal", now);
+        }
+        assert_eq!(throttle.retry_after(attacker, "graal", now), None);
+        throttle.record_failure(attacker, "graal", now);
+        assert_eq!(throttle.retry_after(attacker, "graal", now), Some(LOCKOUT));
+        assert_eq!(throttle.retry_after(attacker, "graal", now + LOCKOUT), None);
+        assert_eq!(throttle.retry_after(attacker, "other", now), None);
+        // The owner logging in from elsewhere is not locked out.
+        assert_eq!(throttle.retry_after(owner, "graal", now), None);
+    }
+
+    #[test]
+    fn locks_out_addresses_guessing_many_accounts() {
+        let mut throttle = LoginThrottle::default();
+        let now = Instant::now();
+        let (attacker, owner): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        // Never often enough for one account to be locked.
+        for attempt in 0..MAX_ADDRESS_FAILURES {
+            assert_eq!(throttle.retry_after(attacker, "graal", now), None);
+            throttle.record_failure(attacker, &format!("account{attempt}"), now);
+        }
+        assert_eq!(throttle.retry_after(attacker, "graal", now), Some(LOCKOUT));
+        // Logging into an account of its own does not lift it.
+        throttle.record_success(attacker, "account0");
+        assert_eq!(throttle.retry_after(attacker, "account0", now), Some(LOCKOUT));
+        assert_eq!(throttle.retry_after(attacker, "graal", now + LOCKOUT), None);
+        assert_eq!(throttle.retry_after(owner, "graal", now), None);
+    }
+}
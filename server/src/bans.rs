@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::normalize_account;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub reason: String,
+    /// Unix time in seconds the ban lifts at, `None` if it is permanent.
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    fn is_active(&self, now: SystemTime) -> bool {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Bans {
+    ips: HashMap<IpAddr, Ban>,
+    accounts: HashMap<String, Ban>,
+}
+
+#[derive(Debug)]
+pub enum BanError {
+    Io(io::Error),
+    Corrupt(serde_json::Error),
+}
+
+impl fmt::Display for BanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "ban list i/o error: {err}"),
+            Self::Corrupt(err) => write!(f, "ban list is corrupt: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BanError {}
+
+/// Banned addresses and accounts. Lists opened from a file rewrite it on every change, through a
+/// temporary file like `FileAccountStore`; the default list only lives in memory.
+#[derive(Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Mutex<Bans>,
+}
+
+impl BanList {
+    /// Loads the bans in `path`, starting empty if the file does not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BanError> {
+        let path = path.as_ref().to_path_buf();
+        let bans = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(BanError::Corrupt)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Bans::default(),
+            Err(e) => return Err(BanError::Io(e)),
+        };
+        Ok(Self {
+            path: Some(path),
+            bans: Mutex::new(bans),
+        })
+    }
+
+    /// Active ban of `ip`, if any.
+    pub fn ip_ban(&self, ip: IpAddr, now: SystemTime) -> Option<Ban> {
+        self.bans.lock().unwrap().ips.get(&ip).filter(|ban| ban.is_active(now)).cloned()
+    }
+
+    /// Active ban of `account`, if any.
+    pub fn account_ban(&self, account: &str, now: SystemTime) -> Option<Ban> {
+        let account = normalize_account(account);
+        self.bans.lock().unwrap().accounts.get(&account).filter(|ban| ban.is_active(now)).cloned()
+    }
+
+    pub fn ban_ip(&self, ip: IpAddr, ban: Ban) -> Result<(), BanError> {
+        self.update(|bans| {
+            bans.ips.insert(ip, ban);
+        })
+    }
+
+    pub fn unban_ip(&self, ip: IpAddr) -> Result<(), BanError> {
+        self.update(|bans| {
+            bans.ips.remove(&ip);
+        })
+    }
+
+    pub fn ban_account(&self, account: &str, ban: Ban) -> Result<(), BanError> {
+        let account = normalize_account(account);
+        self.update(|bans| {
+            bans.accounts.insert(account, ban);
+        })
+    }
+
+    pub fn unban_account(&self, account: &str) -> Result<(), BanError> {
+        let account = normalize_account(account);
+        self.update(|bans| {
+            bans.accounts.remove(&account);
+        })
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Bans)) -> Result<(), BanError> {
+        let mut bans = self.bans.lock().unwrap();
+        change(&mut bans);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_vec_pretty(&*bans).map_err(BanError::Corrupt)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).map_err(BanError::Io)?;
+        fs::rename(&tmp_path, path).map_err(BanError::Io)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn persists_bans_until_they_expire() {
+        let path = std::env::temp_dir().join(format!("bans-{}.json", uuid::Uuid::new_v4()));
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        {
+            let bans = BanList::open(&path).unwrap();
+            bans.ban_ip(ip, Ban { reason: "spam".to_string(), expires_at: Some(2000) }).unwrap();
+            bans.ban_account("Griefer", Ban { reason: "griefing".to_string(), expires_at: None }).unwrap();
+        }
+
+        let bans = BanList::open(&path).unwrap();
+        assert_eq!(bans.ip_ban(ip, now).unwrap().reason, "spam");
+        assert!(bans.ip_ban(ip, UNIX_EPOCH + Duration::from_secs(2000)).is_none());
+        assert!(bans.account_ban("griefer", now).is_some());
+        bans.unban_account("GRIEFER").unwrap();
+        assert!(BanList::open(&path).unwrap().account_ban("griefer", now).is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
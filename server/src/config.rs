@@ -0,0 +1,283 @@
+use std::{fmt, fs, io, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+/// Read when no `--config` is given, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "server.json";
+/// Prefix of the environment variables overriding config keys, e.g. `SERVER_PORT`.
+const ENV_PREFIX: &str = "SERVER_";
+const KEYS: &[&str] = &[
+    "host",
+    "port",
+    "tick_rate",
+    "asset_dir",
+    "data_dir",
+    "log_level",
+    "max_connections",
+    "max_connections_per_ip",
+    "shutdown_countdown",
+];
+
+pub const USAGE: &str = "\
+Usage: server [--config <path>] [--<key> <value>]...
+
+Settings are read from the config file (server.json by default), then overridden by
+SERVER_<KEY> environment variables, then by command line flags.
+
+Keys:
+  host                   Address to bind to (localhost)
+  port                   Port to listen on (3310)
+  tick-rate              Realm ticks per second (20)
+  asset-dir              Directory the maps are loaded from (client/assets)
+  data-dir               Directory of the account, player and ban files (.)
+  log-level              off, error, warn, info, debug or trace (info)
+  max-connections        Connections accepted at once (1024)
+  max-connections-per-ip Connections accepted at once from one address (8)
+  shutdown-countdown     Seconds clients are warned before a shutdown (10)";
+
+/// Everything the server binary can be configured with. File keys use snake_case, flags and
+/// environment variables the same names in kebab-case and SCREAMING_CASE.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub tick_rate: u32,
+    pub asset_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub log_level: String,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub shutdown_countdown: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 3310,
+            tick_rate: 20,
+            asset_dir: PathBuf::from("client/assets"),
+            data_dir: PathBuf::from("."),
+            log_level: "info".to_string(),
+            max_connections: 1024,
+            max_connections_per_ip: 8,
+            shutdown_countdown: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, err: io::Error },
+    Parse { path: PathBuf, err: serde_json::Error },
+    UnknownArgument(String),
+    MissingValue(String),
+    /// `value` could not be parsed for `key`.
+    InvalidValue { key: String, value: String },
+    /// The value of `key` parsed, but is not usable.
+    Invalid { key: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "failed to read [{}]: {err}", path.display()),
+            Self::Parse { path, err } => write!(f, "failed to parse [{}]: {err}", path.display()),
+            Self::UnknownArgument(arg) => write!(f, "unknown argument [{arg}]"),
+            Self::MissingValue(arg) => write!(f, "missing value for [{arg}]"),
+            Self::InvalidValue { key, value } => write!(f, "invalid value [{value}] for [{key}]"),
+            Self::Invalid { key, reason } => write!(f, "[{key}] {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    /// Builds the config from the config file, `env` and the command line `args` (without the
+    /// program name), each overriding the previous one, then validates it. Without `--config`,
+    /// the file is read from `default_path` if it exists.
+    pub fn load(
+        args: &[String],
+        env: impl IntoIterator<Item = (String, String)>,
+        default_path: &Path,
+    ) -> Result<Self, ConfigError> {
+        let mut flags = Vec::new();
+        let mut config_path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(key) = arg.strip_prefix("--") else {
+                return Err(ConfigError::UnknownArgument(arg.clone()));
+            };
+            let value = args.next().ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+            if key == "config" {
+                config_path = Some(PathBuf::from(value));
+            } else {
+                flags.push((key.replace('-', "_"), value.clone()));
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => ServerConfig::read(&path)?,
+            None if default_path.exists() => ServerConfig::read(default_path)?,
+            None => ServerConfig::default(),
+        };
+        for (name, value) in env {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                let key = key.to_ascii_lowercase();
+                // Other SERVER_ variables may belong to something else, only known keys count.
+                if KEYS.contains(&key.as_str()) {
+                    config.set(&key, &value)?;
+                }
+            }
+        }
+        for (key, value) in flags {
+            if !KEYS.contains(&key.as_str()) {
+                return Err(ConfigError::UnknownArgument(format!("--{}", key.replace('_', "-"))));
+            }
+            config.set(&key, &value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read(path).map_err(|err| ConfigError::Io { path: path.to_path_buf(), err })?;
+        serde_json::from_slice(&contents).map_err(|err| ConfigError::Parse { path: path.to_path_buf(), err })
+    }
+
+    /// Overrides `key`, one of `KEYS`, with `value`.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = parse(key, value)?,
+            "tick_rate" => self.tick_rate = parse(key, value)?,
+            "asset_dir" => self.asset_dir = PathBuf::from(value),
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "log_level" => self.log_level = value.to_string(),
+            "max_connections" => self.max_connections = parse(key, value)?,
+            "max_connections_per_ip" => self.max_connections_per_ip = parse(key, value)?,
+            "shutdown_countdown" => self.shutdown_countdown = parse(key, value)?,
+            _ => return Err(ConfigError::UnknownArgument(key.to_string())),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key, reason: &str| Err(ConfigError::Invalid { key, reason: reason.to_string() });
+        if self.host.is_empty() {
+            return invalid("host", "must not be empty");
+        }
+        if self.port == 0 {
+            return invalid("port", "must not be 0");
+        }
+        if !(1..=1000).contains(&self.tick_rate) {
+            return invalid("tick_rate", "must be between 1 and 1000");
+        }
+        if !self.asset_dir.is_dir() {
+            return Err(ConfigError::Invalid {
+                key: "asset_dir",
+                reason: format!("[{}] is not a directory", self.asset_dir.display()),
+            });
+        }
+        if !self.data_dir.is_dir() {
+            return Err(ConfigError::Invalid {
+                key: "data_dir",
+                reason: format!("[{}] is not a directory", self.data_dir.display()),
+            });
+        }
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return invalid("log_level", "must be off, error, warn, info, debug or trace");
+        }
+        if self.max_connections == 0 {
+            return invalid("max_connections", "must be at least 1");
+        }
+        if !(1..=self.max_connections).contains(&self.max_connections_per_ip) {
+            return invalid("max_connections_per_ip", "must be between 1 and max_connections");
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.log_level).unwrap_or(LevelFilter::Info)
+    }
+
+    pub fn tick_length(&self) -> Duration {
+        Duration::from_secs(1) / self.tick_rate
+    }
+
+    pub fn shutdown_countdown(&self) -> Duration {
+        Duration::from_secs(self.shutdown_countdown)
+    }
+
+    pub fn accounts_path(&self) -> PathBuf {
+        self.data_dir.join("accounts.json")
+    }
+
+    pub fn players_path(&self) -> PathBuf {
+        self.data_dir.join("players.redb")
+    }
+
+    pub fn bans_path(&self) -> PathBuf {
+        self.data_dir.join("bans.json")
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue { key: key.to_string(), value: value.to_string() })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Stands in for the default config file, so a `server.json` in the working directory
+    /// cannot change the outcome.
+    fn no_config_file() -> PathBuf {
+        std::env::temp_dir().join(format!("server-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn flags_override_environment() {
+        let env = [
+            ("SERVER_PORT".to_string(), "4000".to_string()),
+            ("SERVER_TICK_RATE".to_string(), "30".to_string()),
+            ("SERVER_UNRELATED".to_string(), "ignored".to_string()),
+        ];
+        let config = ServerConfig::load(&args(&["--asset-dir", "fixtures/maps", "--port", "5000"]), env, &no_config_file()).unwrap();
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.tick_rate, 30);
+        assert_eq!(config.tick_length(), Duration::from_millis(33) + Duration::from_nanos(333_333));
+        assert_eq!(config.host, "localhost");
+    }
+
+    #[test]
+    fn reads_the_default_config_file() {
+        let path = no_config_file();
+        fs::write(&path, r#"{ "port": 4000, "asset_dir": "fixtures/maps" }"#).unwrap();
+        let config = ServerConfig::load(&[], [], &path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.unwrap().port, 4000);
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let load = |flags: &[&str]| {
+            let mut all = args(&["--asset-dir", "fixtures/maps"]);
+            all.extend(args(flags));
+            ServerConfig::load(&all, [], &no_config_file())
+        };
+        assert!(matches!(load(&["--port", "http"]), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(load(&["--tick-rate", "0"]), Err(ConfigError::Invalid { key: "tick_rate", .. })));
+        assert!(matches!(load(&["--log-level", "loud"]), Err(ConfigError::Invalid { key: "log_level", .. })));
+        assert!(matches!(load(&["--max-connections", "4", "--max-connections-per-ip", "5"]), Err(ConfigError::Invalid { key: "max_connections_per_ip", .. })));
+        assert!(matches!(load(&["--verbose", "yes"]), Err(ConfigError::UnknownArgument(_))));
+        assert!(matches!(load(&["--port"]), Err(ConfigError::MissingValue(_))));
+    }
+}
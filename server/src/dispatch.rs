@@ -0,0 +1,173 @@
+use std::{collections::HashMap, fmt};
+
+use shared::client_messages::{ClientOpcode, FromClient};
+use uuid::Uuid;
+
+use crate::session::SessionState;
+
+/// Core responsible for handling a client message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Nexus,
+    Realm,
+}
+
+/// Session state a message is accepted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement {
+    AwaitingLogin,
+    Playing,
+}
+
+impl Requirement {
+    fn allows(self, session: &SessionState) -> bool {
+        match self {
+            Self::AwaitingLogin => matches!(session, SessionState::AwaitingLogin),
+            Self::Playing => matches!(session, SessionState::Playing { .. }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    handler: Handler,
+    requirement: Requirement,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    Nexus,
+    /// Realm handlers act on the sender's entity, so only playing sessions get here.
+    Realm {
+        entity_identifier: Uuid,
+    },
+    Rejected(Rejection),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    NoSession,
+    Unroutable(ClientOpcode),
+    NotAllowed {
+        opcode: ClientOpcode,
+        state: &'static str,
+    },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSession => write!(f, "no session for connection"),
+            Self::Unroutable(opcode) => write!(f, "no handler registered for [{opcode:?}]"),
+            Self::NotAllowed { opcode, state } => {
+                write!(f, "[{opcode:?}] is not allowed while [{state}]")
+            }
+        }
+    }
+}
+
+/// Routes client messages to the core that handles them, based on the sender's session state.
+#[derive(Default)]
+pub struct Dispatcher {
+    routes: HashMap<ClientOpcode, Route>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        mut self,
+        opcode: ClientOpcode,
+        handler: Handler,
+        requirement: Requirement,
+    ) -> Self {
+        self.routes.insert(opcode, Route { handler, requirement });
+        self
+    }
+
+    pub fn dispatch(&self, session: Option<&SessionState>, message: &FromClient) -> Dispatch {
+        let opcode = message.opcode();
+        let Some(route) = self.routes.get(&opcode) else {
+            return Dispatch::Rejected(Rejection::Unroutable(opcode));
+        };
+        let Some(session) = session else {
+            return Dispatch::Rejected(Rejection::NoSession);
+        };
+        if !route.requirement.allows(session) {
+            return Dispatch::Rejected(Rejection::NotAllowed {
+                opcode,
+                state: session.name(),
+            });
+        }
+        match (route.handler, session) {
+            (Handler::Nexus, _) => Dispatch::Nexus,
+            (Handler::Realm, SessionState::Playing { entity_identifier, .. }) => Dispatch::Realm {
+                entity_identifier: *entity_identifier,
+            },
+            (Handler::Realm, session) => Dispatch::Rejected(Rejection::NotAllowed {
+                opcode,
+                state: session.name(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shared::client_messages::{Handshake, Login, MoveInput};
+
+    use super::*;
+    use crate::nexus_core::NexusCore;
+
+    fn playing() -> SessionState {
+        SessionState::Playing {
+            entity_identifier: Uuid::nil(),
+            account: "account".to_string(),
+            display_name: "name".to_string(),
+            current_map: "map".to_string(),
+        }
+    }
+
+    fn login() -> FromClient {
+        FromClient::Login(Login { account: "account".to_string(), password: "password".to_string() })
+    }
+
+    #[test]
+    fn rejects_unregistered_opcode() {
+        // Handshakes never leave networking, nothing routes them.
+        let dispatch = NexusCore::routes().dispatch(
+            Some(&SessionState::AwaitingLogin),
+            &FromClient::Handshake(Handshake::current()),
+        );
+        assert_eq!(dispatch, Dispatch::Rejected(Rejection::Unroutable(ClientOpcode::Handshake)));
+    }
+
+    #[test]
+    fn rejects_message_in_wrong_state() {
+        let dispatcher = NexusCore::routes();
+        let movement = FromClient::MoveInput(MoveInput { sequence: 0, dx: 1.0, dy: 0.0, timestamp_ms: 0 });
+        assert!(matches!(
+            dispatcher.dispatch(Some(&SessionState::AwaitingLogin), &movement),
+            Dispatch::Rejected(Rejection::NotAllowed { .. })
+        ));
+        assert_eq!(
+            dispatcher.dispatch(Some(&playing()), &movement),
+            Dispatch::Realm { entity_identifier: Uuid::nil() }
+        );
+        assert!(matches!(
+            dispatcher.dispatch(Some(&playing()), &login()),
+            Dispatch::Rejected(Rejection::NotAllowed { .. })
+        ));
+        assert_eq!(dispatcher.dispatch(Some(&SessionState::AwaitingLogin), &login()), Dispatch::Nexus);
+    }
+
+    #[test]
+    fn needs_a_session() {
+        assert_eq!(
+            NexusCore::routes().dispatch(None, &login()),
+            Dispatch::Rejected(Rejection::NoSession)
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::networking_core::ConnectionId;
+
+/// Named sets of connections that can be sent a message as a whole, such as everyone on a map.
+#[derive(Default)]
+pub struct Groups {
+    members: HashMap<String, HashSet<ConnectionId>>,
+}
+
+impl Groups {
+    /// Returns whether `connection` was not in `group` yet.
+    pub fn join(&mut self, group: &str, connection: ConnectionId) -> bool {
+        self.members.entry(group.to_string()).or_default().insert(connection)
+    }
+
+    /// Returns whether `connection` was in `group`. Groups are dropped once empty.
+    pub fn leave(&mut self, group: &str, connection: ConnectionId) -> bool {
+        let Some(members) = self.members.get_mut(group) else {
+            return false;
+        };
+        let removed = members.remove(&connection);
+        if members.is_empty() {
+            self.members.remove(group);
+        }
+        removed
+    }
+
+    pub fn leave_all(&mut self, connection: ConnectionId) {
+        self.members.retain(|_, members| {
+            members.remove(&connection);
+            !members.is_empty()
+        });
+    }
+
+    pub fn members(&self, group: &str) -> impl Iterator<Item = ConnectionId> + '_ {
+        self.members.get(group).into_iter().flatten().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    #[test]
+    fn drops_groups_once_empty() {
+        let counter = AtomicU64::new(0);
+        let (a, b) = (ConnectionId::next(&counter), ConnectionId::next(&counter));
+        let mut groups = Groups::default();
+        assert!(groups.join("map:town", a));
+        assert!(!groups.join("map:town", a));
+        groups.join("map:town", b);
+        groups.join("party:1", a);
+
+        assert!(groups.leave("map:town", b));
+        assert!(!groups.leave("map:town", b));
+        assert_eq!(groups.members("map:town").collect::<Vec<_>>(), vec![a]);
+
+        groups.leave_all(a);
+        assert!(groups.is_empty());
+        assert_eq!(groups.members("map:town").count(), 0);
+    }
+}
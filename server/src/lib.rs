@@ -1,8 +1,15 @@
 #[macro_use]
 extern crate log;
 
+pub mod account;
+pub mod bans;
+pub mod config;
 pub mod realm;
 
+pub mod dispatch;
+pub mod groups;
 pub mod networking_core;
 pub mod nexus_core;
+pub mod persistence;
+pub mod rate_limit;
 pub mod session;
@@ -1,12 +1,115 @@
-use server::{networking_core::{NetworkingCore}, nexus_core::NexusCore, realm::realm_core::RealmCore};
+use std::{path::Path, sync::Arc, time::Duration};
+
+use server::{account::file::FileAccountStore, bans::BanList, config::{self, ServerConfig}, persistence::redb::RedbPlayerStore, networking_core::{self, ConnectionSettings, NetworkingCore}, nexus_core::{NexusCommand, NexusCore}, realm::realm_core::{self, RealmCore}};
+use shared::supervisor::{RestartStrategy, Supervisor};
 use simple_logger::SimpleLogger;
 
+/// How long an orderly shutdown may take past its countdown before the process just exits.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(20);
+
 #[tokio::main]
 async fn main() {
-    SimpleLogger::new().env().init().unwrap();
-    let networking_core = NetworkingCore::new().start("localhost".to_string(), 3310);
-    let realm_core = RealmCore::new().start();
-    let nexus_core = NexusCore::new().start(networking_core, realm_core);
-    let (handle, _tx, _rx) = nexus_core.into_parts();
-    let _ = handle.await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::USAGE);
+        return;
+    }
+    let config = match ServerConfig::load(&args, std::env::vars(), Path::new(config::DEFAULT_CONFIG_PATH)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid server configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    SimpleLogger::new().with_level(config.log_level()).env().init().unwrap();
+    log::info!("Starting with {config:?}");
+    let mut supervisor = Supervisor::new(RestartStrategy::default());
+    let bans = match BanList::open(config.bans_path()) {
+        Ok(bans) => Arc::new(bans),
+        Err(e) => {
+            log::error!("Failed to open ban list: {e}");
+            return;
+        }
+    };
+    let settings = ConnectionSettings {
+        max_connections: config.max_connections,
+        max_connections_per_ip: config.max_connections_per_ip,
+        ..ConnectionSettings::default()
+    };
+    let mut networking = NetworkingCore::new().with_settings(settings).with_ban_list(bans.clone());
+    let (host, port) = (config.host.clone(), config.port);
+    let networking_core = supervisor.supervise(networking_core::CORE_NAME, move || {
+        networking.start(host.clone(), port.into())
+    });
+    let mut realm = RealmCore::new()
+        .with_asset_path(config.asset_dir.to_string_lossy())
+        .with_tick_length(config.tick_length());
+    let realm_core = supervisor.supervise(realm_core::CORE_NAME, move || realm.start());
+    let account_store = match FileAccountStore::open(config.accounts_path()) {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("Failed to open account store: {e}");
+            return;
+        }
+    };
+    let player_store = match RedbPlayerStore::open(config.players_path()) {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("Failed to open player store: {e}");
+            return;
+        }
+    };
+    let nexus_core = NexusCore::new()
+        .with_account_store(Arc::new(account_store))
+        .with_player_store(Arc::new(player_store))
+        .with_ban_list(bans)
+        .with_shutdown_countdown(config.shutdown_countdown())
+        .with_supervisor_events(supervisor.take_events().unwrap())
+        .start(networking_core, realm_core);
+    let (mut handle, tx, _rx) = nexus_core.into_parts();
+    tokio::select! {
+        _ = &mut handle => return,
+        _ = shutdown_signal() => {}
+    }
+    log::info!("Shutdown requested");
+    if tx.send(NexusCommand::Shutdown).await.is_err() {
+        return;
+    }
+    let deadline = config.shutdown_countdown() + SHUTDOWN_GRACE;
+    tokio::select! {
+        finished = tokio::time::timeout(deadline, handle) => {
+            if finished.is_err() {
+                log::error!("Shutdown did not finish within [{}s], exiting", deadline.as_secs());
+            }
+        }
+        _ = shutdown_signal() => log::warn!("Shutdown requested again, exiting"),
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Failed to listen for SIGINT: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
@@ -1,17 +1,39 @@
-use std::{net::{SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+use std::{net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime}};
 
 use dashmap::DashMap;
-use shared::{client_messages::{ClientOpcode, FromClient}, core::Core, frame::{MessageFrame, MsgCodec, Opcode}, server_messages::{FromServer, ServerOpcode}};
-use tokio::{sync::mpsc, task::JoinHandle};
-use tokio::net::{TcpListener, TcpStream};
+use shared::{channel::{self, ChannelConfig, OverflowPolicy, Receiver, SendError, Sender}, client_messages::{ClientOpcode, FromClient}, core::{Core, Reply}, frame::{FrameError, MessageFrame, MsgCodec, Opcode, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_MAX_PAYLOAD_LEN}, protocol::{features, negotiate, Negotiated, Ping, Pong}, server_messages::{DisconnectReason, FromServer, Handshake, ServerOpcode}};
+use tokio::{sync::{mpsc, Semaphore}, task::JoinHandle, time::Instant};
+use tokio::net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener, TcpStream};
 use tokio_util::{codec::{FramedRead, FramedWrite}, sync::CancellationToken};
 use futures::{SinkExt, StreamExt};
 
+use crate::{bans::BanList, groups::Groups, rate_limit::{RateLimiter, RateLimits, Violation}};
+
+/// Name the networking core is supervised under.
+pub const CORE_NAME: &str = "networking";
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Refused connections told why at once. Any beyond that are closed without a word, so a flood
+/// of refused connections costs a bounded number of tasks.
+const MAX_REFUSALS: usize = 64;
+/// How long a refused connection gets to complete its handshake and read the reason.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long connections get to flush their queue and say goodbye when the core stops.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+const BIND_ATTEMPTS: u32 = 5;
+/// Wait before the second bind attempt, doubled for each later one.
+const BIND_BACKOFF: Duration = Duration::from_millis(100);
+/// Weight of a new round trip sample in the smoothed RTT, as in TCP's SRTT.
+const RTT_SMOOTHING: f64 = 0.125;
+
+type ClientReader = FramedRead<OwnedReadHalf, MsgCodec<ClientOpcode>>;
+type ServerWriter = FramedWrite<OwnedWriteHalf, MsgCodec<ServerOpcode>>;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ConnectionId(u64);
 
 impl ConnectionId {
-    fn next(counter: &AtomicU64) -> Self {
+    pub(crate) fn next(counter: &AtomicU64) -> Self {
         let id = counter.fetch_add(1, Ordering::Relaxed) + 1;
         Self(id)
     }
@@ -20,6 +42,7 @@ impl ConnectionId {
 pub enum NetEvent {
     NewConnection {
         connection_id: ConnectionId,
+        address: SocketAddr,
     },
     Disconnected {
         connection_id: ConnectionId,
@@ -28,10 +51,20 @@ pub enum NetEvent {
         connection_id: ConnectionId,
         message: FromClient,
     },
+    /// The connection was dropped for abuse. `Disconnected` follows.
+    Kicked {
+        connection_id: ConnectionId,
+        /// Violation that used up the connection's tolerance.
+        violation: Violation,
+        violations: u32,
+    },
 }
 
 pub enum NetCommand {
+    /// Disconnects every client with `DisconnectReason::ShuttingDown`, then stops the core.
     Stop,
+    /// Closes the listener. Established connections are left alone.
+    StopAccepting,
     Send {
         connection: ConnectionId,
         message: FromServer,
@@ -39,21 +72,111 @@ pub enum NetCommand {
     Broadcast {
         message: FromServer,
     },
+    /// Sends one message to several connections, serializing it once.
+    Multicast {
+        connections: Vec<ConnectionId>,
+        message: FromServer,
+    },
+    JoinGroup {
+        group: String,
+        connection: ConnectionId,
+    },
+    LeaveGroup {
+        group: String,
+        connection: ConnectionId,
+    },
+    /// Multicasts to every member of `group`.
+    SendToGroup {
+        group: String,
+        message: FromServer,
+    },
+    /// Sends `reason` to `connection` after whatever is already queued for it, then closes it.
+    Disconnect {
+        connection: ConnectionId,
+        reason: DisconnectReason,
+    },
+    /// Smoothed round trip time of `connection`, `None` until it answered a ping.
+    GetRtt {
+        connection: ConnectionId,
+        reply: Reply<Option<Duration>>,
+    },
+}
+
+/// What a connection's task is asked to do with its socket, in order.
+#[derive(Debug)]
+enum Outbound {
+    Frame(MessageFrame<ServerOpcode>),
+    Close(DisconnectReason),
 }
 
 #[derive(Debug)]
 pub struct ConnectionRecord {
     pub address: SocketAddr,
-    outgoing: tokio::sync::mpsc::UnboundedSender<MessageFrame<ServerOpcode>>,
+    outgoing: tokio::sync::mpsc::UnboundedSender<Outbound>,
+    rtt: Option<Duration>,
+}
+
+impl ConnectionRecord {
+    /// Smoothed round trip time, once the client answered a ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+            None => sample,
+        });
+    }
 }
 
 type Connections = Arc<DashMap<ConnectionId, ConnectionRecord>>;
 
-#[derive(Default)]
+/// Limits applied to every accepted connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    pub max_payload_len: usize,
+    /// Outbound payloads of at least this many bytes are compressed for clients that negotiated
+    /// `features::COMPRESSION`. `None` disables compression.
+    pub compression_threshold: Option<usize>,
+    /// How often the server pings each client.
+    pub heartbeat_interval: Duration,
+    /// Clients that send nothing for this long, not even a pong, are disconnected.
+    pub idle_timeout: Duration,
+    pub rate_limits: RateLimits,
+    /// Connections accepted at once, across every address.
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            heartbeat_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(30),
+            rate_limits: RateLimits::default(),
+            max_connections: 1024,
+            max_connections_per_ip: 8,
+        }
+    }
+}
+
 pub struct NetworkingCore {
     address: Option<String>,
     connections: Connections,
     id_counter: Arc<AtomicU64>,
+    settings: ConnectionSettings,
+    bans: Arc<BanList>,
+    command_channel: ChannelConfig,
+    event_channel: ChannelConfig,
+}
+
+impl Default for NetworkingCore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkingCore {
@@ -63,9 +186,34 @@ impl NetworkingCore {
             address: None,
             connections: Arc::default(),
             id_counter: Arc::new(AtomicU64::new(0)),
+            settings: ConnectionSettings::default(),
+            bans: Arc::new(BanList::default()),
+            // Login results, disconnects and shutdown notices share this queue with snapshots,
+            // none of it may be dropped. Senders wait for room instead.
+            command_channel: ChannelConfig::bounded(16384, OverflowPolicy::Block),
+            // A full event queue stops connections from reading their sockets, which pushes
+            // back on the clients through TCP.
+            event_channel: ChannelConfig::bounded(4096, OverflowPolicy::Block),
         }
     }
 
+    pub fn with_channels(mut self, commands: ChannelConfig, events: ChannelConfig) -> Self {
+        self.command_channel = commands;
+        self.event_channel = events;
+        self
+    }
+
+    pub fn with_settings(mut self, settings: ConnectionSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Addresses refused at accept time. Defaults to an empty in-memory list.
+    pub fn with_ban_list(mut self, bans: Arc<BanList>) -> Self {
+        self.bans = bans;
+        self
+    }
+
     pub fn start(
         &mut self,
         address: String,
@@ -73,8 +221,11 @@ impl NetworkingCore {
     ) -> Core<NetCommand, NetEvent> {
         info!("Starting Networking Core");
         let cancellation_token = CancellationToken::new();
-        let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel();
-        let (command_tx, command_rx) = tokio::sync::mpsc::unbounded_channel();
+        let accepting = cancellation_token.child_token();
+        let (event_tx, event_rx) = channel::channel(self.event_channel);
+        let (command_tx, command_rx) = channel::channel(self.command_channel);
+        // Connections report here when they close, so the control loop can drop them from groups.
+        let (closed_tx, closed_rx) = mpsc::unbounded_channel();
         self.address = Some(format!("{address}:{port}"));
 
         let listener_handle = NetworkingCore::listener_loop(
@@ -82,13 +233,19 @@ impl NetworkingCore {
             event_tx.clone(),
             self.connections.clone(),
             self.id_counter.clone(),
+            self.settings.clone(),
+            self.bans.clone(),
+            closed_tx,
+            accepting.clone(),
             cancellation_token.clone()
         );
 
         let control_handle = NetworkingCore::control_loop(
             command_rx,
+            closed_rx,
             self.connections.clone(),
             listener_handle,
+            accepting,
             cancellation_token
         );
         Core::new(command_tx.clone(), control_handle)
@@ -96,25 +253,53 @@ impl NetworkingCore {
     }
 
     fn control_loop(
-        mut rx: mpsc::UnboundedReceiver<NetCommand>,
+        mut rx: Receiver<NetCommand>,
+        mut closed: mpsc::UnboundedReceiver<ConnectionId>,
         connections: Connections,
         listener_handle: JoinHandle<()>,
+        accepting: CancellationToken,
         token: CancellationToken,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
+            // Tear down the listener and every connection if this loop panics, so a restarted
+            // core can bind the address again.
+            let _cancel_on_exit = token.clone().drop_guard();
+            let mut groups = Groups::default();
+            let mut listener_handle = Some(listener_handle);
             loop {
-                match rx.recv().await {
+                let command = tokio::select! {
+                    _ = async { listener_handle.as_mut().unwrap().await }, if listener_handle.is_some() => {
+                        listener_handle = None;
+                        // Crash so the supervisor restarts the core, or gives up on it.
+                        if !accepting.is_cancelled() {
+                            panic!("NetworkingCore: Listener stopped unexpectedly");
+                        }
+                        continue;
+                    }
+                    Some(connection) = closed.recv() => {
+                        groups.leave_all(connection);
+                        continue;
+                    }
+                    command = rx.recv() => command,
+                };
+                match command {
                     Some(command) => match command {
                         NetCommand::Stop => {
                             info!("NetworkingCore: Stopping");
                             NetworkingCore::stop(token, listener_handle, connections).await;
                             break;
                         }
+                        NetCommand::StopAccepting => {
+                            if !accepting.is_cancelled() {
+                                info!("NetworkingCore: No longer accepting connections");
+                                accepting.cancel();
+                            }
+                        }
                         NetCommand::Send { connection, message } => {
                             if let Some(connection) = connections.get(&connection) {
                                 match message.serialize() {
                                     Ok(frame) => {
-                                        let _ = connection.outgoing.send(frame);
+                                        let _ = connection.outgoing.send(Outbound::Frame(frame));
                                     }
                                     Err(e) => warn!("Failed to encode message [{message:?}] [{e}]"),
                                 }
@@ -124,7 +309,7 @@ impl NetworkingCore {
                             match message.serialize() {
                                 Ok(frame) => {
                                     for connection in connections.iter() {
-                                        let _ = connection.outgoing.send(frame.clone());
+                                        let _ = connection.outgoing.send(Outbound::Frame(frame.clone()));
                                     }
                                 }
                                 Err(e) => {
@@ -133,6 +318,29 @@ impl NetworkingCore {
                                 }
                             };
                         },
+                        NetCommand::Multicast { connections: targets, message } => {
+                            NetworkingCore::multicast(&connections, &targets, message);
+                        }
+                        NetCommand::JoinGroup { group, connection } => {
+                            if connections.contains_key(&connection) {
+                                groups.join(&group, connection);
+                            }
+                        }
+                        NetCommand::LeaveGroup { group, connection } => {
+                            groups.leave(&group, connection);
+                        }
+                        NetCommand::Disconnect { connection, reason } => {
+                            if let Some(connection) = connections.get(&connection) {
+                                let _ = connection.outgoing.send(Outbound::Close(reason));
+                            }
+                        }
+                        NetCommand::GetRtt { connection, reply } => {
+                            reply.send(connections.get(&connection).and_then(|record| record.rtt()));
+                        }
+                        NetCommand::SendToGroup { group, message } => {
+                            let members: Vec<ConnectionId> = groups.members(&group).collect();
+                            NetworkingCore::multicast(&connections, &members, message);
+                        }
                     }
                     None => {
                         error!("NetworkingCore: Channel closed. Stopping");
@@ -144,25 +352,42 @@ impl NetworkingCore {
         })
     }
 
+    fn multicast(connections: &Connections, targets: &[ConnectionId], message: FromServer) {
+        let frame = match message.serialize() {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Failed to encode message [{message:?}] [{e}]");
+                return;
+            }
+        };
+        for target in targets {
+            if let Some(connection) = connections.get(target) {
+                let _ = connection.outgoing.send(Outbound::Frame(frame.clone()));
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn listener_loop(
         address: String,
-        tx: mpsc::UnboundedSender<NetEvent>,
+        tx: Sender<NetEvent>,
         connections: Connections,
         id_counter: Arc<AtomicU64>,
+        settings: ConnectionSettings,
+        bans: Arc<BanList>,
+        closed: mpsc::UnboundedSender<ConnectionId>,
+        accepting: CancellationToken,
         token: CancellationToken,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let listener = match TcpListener::bind(&address).await {
-                Ok(listener) => listener,
-                Err(err) => {
-                    error!("Failed to bind to address [{address}]: {err}");
-                    return;
-                }
+            let Some(listener) = NetworkingCore::bind(&address).await else {
+                return;
             };
+            let refusals = Arc::new(Semaphore::new(MAX_REFUSALS));
             info!("Listening on: [{address}]");
             loop {
                 tokio::select! {
-                    _ = token.cancelled() => {
+                    _ = accepting.cancelled() => {
                         info!("Listener closed by server");
                         break;
                     },
@@ -174,13 +399,28 @@ impl NetworkingCore {
                                 continue;
                             }
                         };
+                        if let Some(reason) = NetworkingCore::admit(&connections, &bans, &settings, remote_addr.ip()) {
+                            info!("Refusing connection from [{remote_addr}]: {reason:?}");
+                            // Dropping the socket closes it.
+                            let Ok(permit) = refusals.clone().try_acquire_owned() else {
+                                continue;
+                            };
+                            let max_payload_len = settings.max_payload_len;
+                            tokio::spawn(async move {
+                                let refuse = NetworkingCore::refuse(socket, remote_addr, reason, max_payload_len);
+                                let _ = tokio::time::timeout(REFUSE_TIMEOUT, refuse).await;
+                                drop(permit);
+                            });
+                            continue;
+                        }
                         let connection_id = ConnectionId::next(&id_counter);
-                        let (out_tx, out_rx) = mpsc::unbounded_channel::<MessageFrame<ServerOpcode>>();
+                        let (out_tx, out_rx) = mpsc::unbounded_channel::<Outbound>();
                         connections.insert(
                             connection_id,
                             ConnectionRecord {
                                 address: remote_addr,
                                 outgoing: out_tx.clone(),
+                                rtt: None,
                             },
                         );
                         let connection_map = connections.clone();
@@ -191,6 +431,8 @@ impl NetworkingCore {
                             remote_addr,
                             connection_map,
                             out_rx,
+                            closed.clone(),
+                            settings.clone(),
                             token.clone(),
                         ));
                     }
@@ -199,48 +441,127 @@ impl NetworkingCore {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_connection(
         socket: TcpStream,
-        tx: mpsc::UnboundedSender<NetEvent>,
+        tx: Sender<NetEvent>,
         connection_id: ConnectionId,
         remote_addr: SocketAddr,
         connections: Connections,
-        mut outgoing: mpsc::UnboundedReceiver<MessageFrame<ServerOpcode>>,
+        mut outgoing: mpsc::UnboundedReceiver<Outbound>,
+        closed: mpsc::UnboundedSender<ConnectionId>,
+        settings: ConnectionSettings,
         cancel: CancellationToken,
     ) {
         info!("Incoming connection {connection_id:?} from [{remote_addr}]");
         let (read_half, write_half) = socket.into_split();
-        let mut reader = FramedRead::new(read_half, MsgCodec::<ClientOpcode>::default());
-        let mut writer = FramedWrite::new(write_half, MsgCodec::<ServerOpcode>::default());
-        if let Err(e) = tx.send(NetEvent::NewConnection { connection_id }) {
+        let mut reader = FramedRead::new(read_half, MsgCodec::<ClientOpcode>::new(settings.max_payload_len));
+        let mut writer = FramedWrite::new(write_half, MsgCodec::<ServerOpcode>::new(settings.max_payload_len));
+        let Some(negotiated) = NetworkingCore::handshake(&mut reader, &mut writer, remote_addr).await else {
+            connections.remove(&connection_id);
+            info!("Connection removed for [{remote_addr}] (id={connection_id:?})");
+            return;
+        };
+        if negotiated.features & features::COMPRESSION != 0 {
+            writer.encoder_mut().set_compression_threshold(settings.compression_threshold);
+        }
+        if let Err(e) = tx.send(NetEvent::NewConnection { connection_id, address: remote_addr }).await {
             warn!("Event channel closed: [{e}]");
             return;
         }
 
+        let mut heartbeat = tokio::time::interval_at(
+            Instant::now() + settings.heartbeat_interval,
+            settings.heartbeat_interval,
+        );
+        let idle = tokio::time::sleep(settings.idle_timeout);
+        tokio::pin!(idle);
+        // One ping is out at a time. It stays timed until its pong arrives, however many
+        // heartbeats that takes, or the idle timeout gives up on the client.
+        let mut pending_ping: Option<(u32, Instant)> = None;
+        let mut nonce: u32 = 0;
+        let mut limiter = RateLimiter::new(settings.rate_limits.clone(), std::time::Instant::now());
         loop {
             tokio::select! {
                 _ = cancel.cancelled() => {
                     info!("Connection closed by server for [{remote_addr}]");
                     break;
                 },
+                _ = &mut idle => {
+                    info!("Disconnecting idle client [{remote_addr}] (id={connection_id:?})");
+                    NetworkingCore::write(&mut writer, FromServer::Disconnect(DisconnectReason::Idle), remote_addr).await;
+                    break;
+                },
+                _ = heartbeat.tick() => {
+                    if pending_ping.is_some() {
+                        continue;
+                    }
+                    nonce = nonce.wrapping_add(1);
+                    pending_ping = Some((nonce, Instant::now()));
+                    if !NetworkingCore::write(&mut writer, FromServer::Ping(Ping { nonce }), remote_addr).await {
+                        break;
+                    }
+                },
                 inbound = reader.next() => {
+                    if matches!(inbound, Some(Ok(_))) {
+                        idle.as_mut().reset(Instant::now() + settings.idle_timeout);
+                    }
                     match inbound {
                         Some(Ok(frame)) => {
-                            if let Ok(message) = FromClient::deserialize(frame.opcode, &frame.payload) {
-                                if let Err(e) = tx.send(NetEvent::IncomingMessage{ connection_id, message }) {
-                                    warn!("Failed to send message to [{connection_id:?}]: [{e}]");
+                            if !limiter.allow(frame.opcode, std::time::Instant::now()) {
+                                debug!("Dropping {:?} from [{remote_addr}] (id={connection_id:?}): rate limited", frame.opcode);
+                                if NetworkingCore::penalize(&mut limiter, Violation::RateLimited(frame.opcode), connection_id, remote_addr, &tx) {
+                                    NetworkingCore::write(&mut writer, FromServer::Disconnect(DisconnectReason::Abuse), remote_addr).await;
                                     break;
                                 }
+                                continue;
+                            }
+                            if let Ok(message) = FromClient::deserialize(frame.opcode, &frame.payload) {
+                                let message = match message {
+                                    FromClient::Ping(Ping { nonce }) => {
+                                        if !NetworkingCore::write(&mut writer, FromServer::Pong(Pong { nonce }), remote_addr).await {
+                                            break;
+                                        }
+                                        continue;
+                                    }
+                                    FromClient::Pong(pong) => {
+                                        if let Some((nonce, sent_at)) = pending_ping
+                                            && nonce == pong.nonce
+                                        {
+                                            pending_ping = None;
+                                            if let Some(mut record) = connections.get_mut(&connection_id) {
+                                                record.record_rtt(sent_at.elapsed());
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                    message => message,
+                                };
+                                match tx.send(NetEvent::IncomingMessage{ connection_id, message }).await {
+                                    Ok(()) => {}
+                                    Err(e @ SendError::Disconnected(_)) => {
+                                        warn!("Disconnecting [{remote_addr}] (id={connection_id:?}): [{e}]");
+                                        break;
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed to send message to [{connection_id:?}]: [{e}]");
+                                        break;
+                                    }
+                                }
                             } else {
                                 warn!(
                                     "Failed to decode frame from {remote_addr} (id={connection_id:?}): opcode=0x{:#06x}, len={}",
                                     frame.opcode.into_raw(),
                                     frame.payload.len()
                                 );
+                                if NetworkingCore::penalize(&mut limiter, Violation::Undecodable(frame.opcode), connection_id, remote_addr, &tx) {
+                                    NetworkingCore::write(&mut writer, FromServer::Disconnect(DisconnectReason::Abuse), remote_addr).await;
+                                    break;
+                                }
                             }
                         }
                         Some(Err(err)) => {
-                            error!("Failed to decode frame from [{remote_addr}]: {err}");
+                            warn!("Disconnecting [{remote_addr}] (id={connection_id:?}): {err}");
                             break;
                         }
                         None => {
@@ -249,26 +570,269 @@ impl NetworkingCore {
                         }
                     }
                 }
-                Some(payload) = outgoing.recv() => {
-                    if let Err(err) = writer.send(payload).await {
-                        error!("Failed to send outbound frame to [{remote_addr}]: {err}");
-                        break;
+                Some(outbound) = outgoing.recv() => {
+                    let payload = match outbound {
+                        Outbound::Frame(payload) => payload,
+                        Outbound::Close(reason) => {
+                            info!("Disconnecting [{remote_addr}] (id={connection_id:?}): {reason:?}");
+                            NetworkingCore::write(&mut writer, FromServer::Disconnect(reason), remote_addr).await;
+                            break;
+                        }
+                    };
+                    match writer.send(payload).await {
+                        Ok(()) => {}
+                        Err(err @ FrameError::PayloadTooLarge { .. }) => {
+                            warn!("Dropping outbound frame to [{remote_addr}]: {err}");
+                        }
+                        Err(err) => {
+                            error!("Failed to send outbound frame to [{remote_addr}]: {err}");
+                            break;
+                        }
                     }
                 }
             }
         }
 
         connections.remove(&connection_id);
-        let _ = tx.send(NetEvent::Disconnected { connection_id });
+        let _ = closed.send(connection_id);
+        let _ = tx.force_send(NetEvent::Disconnected { connection_id });
         info!("Connection removed for [{remote_addr}] (id={connection_id:?})");
     }
 
+    /// Binds `address`, retrying with backoff: the listener of a crashed core may not have let go
+    /// of it yet.
+    async fn bind(address: &str) -> Option<TcpListener> {
+        let mut backoff = BIND_BACKOFF;
+        for attempt in 1..=BIND_ATTEMPTS {
+            match TcpListener::bind(address).await {
+                Ok(listener) => return Some(listener),
+                Err(err) if attempt < BIND_ATTEMPTS => {
+                    warn!("Failed to bind to address [{address}], retrying in [{backoff:?}]: {err}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => error!("Failed to bind to address [{address}]: {err}"),
+            }
+        }
+        None
+    }
+
+    /// Why a new connection from `ip` must be refused, if it must.
+    fn admit(
+        connections: &Connections,
+        bans: &BanList,
+        settings: &ConnectionSettings,
+        ip: IpAddr,
+    ) -> Option<DisconnectReason> {
+        if let Some(ban) = bans.ip_ban(ip, SystemTime::now()) {
+            return Some(DisconnectReason::Banned { reason: ban.reason, expires_at: ban.expires_at });
+        }
+        if connections.len() >= settings.max_connections {
+            return Some(DisconnectReason::ServerFull);
+        }
+        let from_ip = connections.iter().filter(|connection| connection.address.ip() == ip).count();
+        (from_ip >= settings.max_connections_per_ip).then_some(DisconnectReason::TooManyConnections)
+    }
+
+    /// Completes the handshake of a connection that was not admitted, so the client can be told
+    /// why, then closes it.
+    async fn refuse(socket: TcpStream, remote_addr: SocketAddr, reason: DisconnectReason, max_payload_len: usize) {
+        let (read_half, write_half) = socket.into_split();
+        let mut reader = FramedRead::new(read_half, MsgCodec::<ClientOpcode>::new(max_payload_len));
+        let mut writer = FramedWrite::new(write_half, MsgCodec::<ServerOpcode>::new(max_payload_len));
+        if NetworkingCore::handshake(&mut reader, &mut writer, remote_addr).await.is_some() {
+            NetworkingCore::write(&mut writer, FromServer::Disconnect(reason), remote_addr).await;
+        }
+    }
+
+    /// Counts `violation` against the connection. Returns whether it used up its tolerance, in
+    /// which case the caller must drop the connection.
+    fn penalize(
+        limiter: &mut RateLimiter,
+        violation: Violation,
+        connection_id: ConnectionId,
+        remote_addr: SocketAddr,
+        tx: &Sender<NetEvent>,
+    ) -> bool {
+        if !limiter.record(std::time::Instant::now()) {
+            return false;
+        }
+        let violations = limiter.violations();
+        warn!("Kicking [{remote_addr}] (id={connection_id:?}) after [{violations}] violations, last: {violation}");
+        let _ = tx.force_send(NetEvent::Kicked { connection_id, violation, violations });
+        true
+    }
+
+    /// Writes a message straight to the socket, bypassing the outgoing queue. Returns `false` if
+    /// the connection should be dropped.
+    async fn write(writer: &mut ServerWriter, message: FromServer, remote_addr: SocketAddr) -> bool {
+        let frame = match message.serialize() {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to encode message [{message:?}] for [{remote_addr}]: {e}");
+                return true;
+            }
+        };
+        match writer.send(frame).await {
+            Ok(()) => true,
+            Err(err) => {
+                error!("Failed to send frame to [{remote_addr}]: {err}");
+                false
+            }
+        }
+    }
+
+    /// Waits for the client's handshake and answers it. Returns the negotiated protocol
+    /// parameters, or `None` if the client must be disconnected.
+    async fn handshake(
+        reader: &mut ClientReader,
+        writer: &mut ServerWriter,
+        remote_addr: SocketAddr,
+    ) -> Option<Negotiated> {
+        let handshake = match tokio::time::timeout(HANDSHAKE_TIMEOUT, reader.next()).await {
+            Ok(Some(Ok(frame))) => match FromClient::deserialize(frame.opcode, &frame.payload) {
+                Ok(FromClient::Handshake(handshake)) => handshake,
+                Ok(message) => {
+                    warn!("Expected a handshake from [{remote_addr}], got [{:?}]", message.opcode());
+                    return None;
+                }
+                Err(e) => {
+                    warn!("Failed to decode handshake from [{remote_addr}]: {e}");
+                    return None;
+                }
+            },
+            Ok(Some(Err(err))) => {
+                warn!("Failed to decode handshake from [{remote_addr}]: {err}");
+                return None;
+            }
+            Ok(None) => {
+                info!("Connection closed by client [{remote_addr}] during handshake");
+                return None;
+            }
+            Err(_) => {
+                warn!("Handshake timed out for [{remote_addr}]");
+                return None;
+            }
+        };
+
+        let server_build = shared::build::get_version();
+        let (reply, negotiated) = match negotiate(
+            handshake.protocol_version,
+            handshake.build.as_deref(),
+            handshake.features,
+            server_build.as_deref(),
+        ) {
+            Ok(negotiated) => {
+                info!(
+                    "Handshake accepted for [{remote_addr}]: protocol={}, features={:#x}",
+                    negotiated.protocol_version,
+                    negotiated.features
+                );
+                (
+                    Handshake::Accepted {
+                        protocol_version: negotiated.protocol_version,
+                        features: negotiated.features,
+                    },
+                    Some(negotiated),
+                )
+            }
+            Err(rejection) => {
+                warn!("Rejecting handshake from [{remote_addr}]: {rejection}");
+                (Handshake::Rejected(rejection), None)
+            }
+        };
+
+        match FromServer::Handshake(reply).serialize() {
+            Ok(frame) => {
+                if let Err(err) = writer.send(frame).await {
+                    error!("Failed to send handshake to [{remote_addr}]: {err}");
+                    return None;
+                }
+            }
+            Err(e) => {
+                error!("Failed to encode handshake for [{remote_addr}]: {e}");
+                return None;
+            }
+        }
+        negotiated
+    }
+
+    /// Asks every connection to close, giving them `CLOSE_TIMEOUT` to do so before tearing down
+    /// whatever is left.
     async fn stop(
         cancel: CancellationToken,
-        listener_handle: JoinHandle<()>,
-        _connections: Connections,
+        listener_handle: Option<JoinHandle<()>>,
+        connections: Connections,
     ) {
+        for connection in connections.iter() {
+            let _ = connection.outgoing.send(Outbound::Close(DisconnectReason::ShuttingDown));
+        }
+        let drained = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while !connections.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("[{}] connections did not close in time", connections.len());
+        }
         cancel.cancel();
-        let _ = listener_handle.await;
+        if let Some(listener_handle) = listener_handle {
+            let _ = listener_handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn smooths_round_trip_samples() {
+        let (outgoing, _rx) = mpsc::unbounded_channel();
+        let mut record = ConnectionRecord { address: "127.0.0.1:1".parse().unwrap(), outgoing, rtt: None };
+        record.record_rtt(Duration::from_millis(80));
+        assert_eq!(record.rtt(), Some(Duration::from_millis(80)));
+        // A single spike only moves the estimate by an eighth of the difference.
+        record.record_rtt(Duration::from_millis(880));
+        assert_eq!(record.rtt(), Some(Duration::from_millis(180)));
+    }
+
+    #[tokio::test]
+    async fn bounds_refused_connections() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let settings = ConnectionSettings { max_connections: 0, ..ConnectionSettings::default() };
+        let core = NetworkingCore::new().with_settings(settings).start("127.0.0.1".to_string(), port.into());
+        let connect = || tokio::net::TcpStream::connect(("127.0.0.1", port));
+        // The listener binds in the background.
+        let mut first = connect().await;
+        while first.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            first = connect().await;
+        }
+        let mut stalled = vec![first.unwrap()];
+        for _ in 1..MAX_REFUSALS {
+            stalled.push(connect().await.unwrap());
+        }
+        // Every refusal slot is held by a client that never completes its handshake.
+        let closed_at_once = |mut socket: TcpStream| async move {
+            let mut buffer = [0; 64];
+            tokio::time::timeout(REFUSE_TIMEOUT / 2, tokio::io::AsyncReadExt::read(&mut socket, &mut buffer)).await
+        };
+        assert!(matches!(closed_at_once(connect().await.unwrap()).await, Ok(Ok(0))));
+        // The stalled ones are closed once they run out of time.
+        let mut buffer = [0; 64];
+        let read = tokio::time::timeout(REFUSE_TIMEOUT * 2, tokio::io::AsyncReadExt::read(&mut stalled[0], &mut buffer)).await;
+        assert!(matches!(read, Ok(Ok(0))));
+        core.stop(Some(NetCommand::Stop)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn crashes_when_the_address_stays_taken() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let core = NetworkingCore::new().start("127.0.0.1".to_string(), port.into());
+        let result = tokio::time::timeout(Duration::from_secs(10), core.handle).await.unwrap();
+        assert!(result.unwrap_err().is_panic());
     }
 }
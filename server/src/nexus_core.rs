@@ -1,25 +1,37 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc, time::{Duration, Instant, SystemTime}};
 
 use dashmap::{DashMap, mapref::one::RefMut};
-use shared::core::Core;
+use shared::{channel::{self, ChannelConfig, OverflowPolicy, Receiver, Sender}, client_messages::{ClientOpcode, FromClient}, core::{Core, Reply, request}, server_messages::{DisconnectReason, FromServer, LoginResult, ShutdownNotice}, supervisor::SupervisorEvent};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::{session::SessionState, networking_core::{ConnectionId, NetCommand, NetEvent}, realm::realm_core::{RealmCommand, RealmEvent}};
+use crate::{account::{self, AccountStore, memory::InMemoryAccountStore, throttle::LoginThrottle}, bans::BanList, dispatch::{Dispatch, Dispatcher, Handler, Requirement}, session::SessionState, networking_core::{self, ConnectionId, NetCommand, NetEvent}, persistence::{PlayerRecord, PlayerStore, memory::InMemoryPlayerStore, writer::{self, StoreCommand}}, realm::realm_core::{self, RealmCommand, RealmEvent}};
+
+/// How long the realm or networking core gets to answer the nexus.
+const CORE_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the health of the other cores is logged.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a load or a final save may wait behind the saves queued before it.
+const STORE_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub enum NexusCommand {
+    /// Saves every player, stops the realm and disconnects every client, then stops the nexus.
     Stop,
-    RegisterConnection {
+    /// Stops accepting connections and warns clients for the shutdown countdown, then `Stop`s.
+    Shutdown,
+    UnregisterConnection {
         connection_id: ConnectionId,
     },
-    UnregisterConnection {
+    /// The realm saved and removed the player of a closed connection, its account is free again.
+    PlayerDespawned {
         connection_id: ConnectionId,
     },
     RegisterPlayer {
         connection_id: ConnectionId,
         entity_identifier: Uuid,
         account: String,
+        display_name: String,
         current_map: String,
     },
     SetDisplayName {
@@ -30,12 +42,61 @@ pub enum NexusCommand {
         entity_identifier: Uuid,
         new_map: String,
     },
+    /// Map `account` is playing on, if it is playing.
+    GetCurrentMap {
+        account: String,
+        reply: Reply<Option<String>>,
+    },
+    /// A client message routed to the nexus by the dispatcher.
+    ClientMessage {
+        connection_id: ConnectionId,
+        /// Address the connection came from.
+        ip: IpAddr,
+        message: FromClient,
+    },
+    /// Reserves `account` for a connection whose credentials were accepted. Answers false if
+    /// another session holds it.
+    ClaimAccount {
+        connection_id: ConnectionId,
+        account: String,
+        reply: Reply<bool>,
+    },
+    /// Outcome of the credential check started for a `Login` or `Register` message.
+    LoginVerified {
+        connection_id: ConnectionId,
+        ip: IpAddr,
+        account: String,
+        result: LoginResult,
+        /// Saved character, loaded once the credentials were accepted.
+        record: Option<PlayerRecord>,
+    },
+    /// A supervised core crashed and was restarted with fresh state.
+    CoreRestarted {
+        core: &'static str,
+        /// Connections that had a session when the restart was reported. A networking restart
+        /// took down these, and only these.
+        connections: Vec<ConnectionId>,
+    },
 }
 
-#[derive(Default)]
 pub struct NexusCore {
     sessions: Arc<DashMap<ConnectionId, SessionState>>,
     identifiers: Arc<DashMap<Uuid, ConnectionId>>,
+    /// Address each connection came from, for the login throttle.
+    addresses: Arc<DashMap<ConnectionId, IpAddr>>,
+    command_channel: ChannelConfig,
+    supervisor_events: Option<Receiver<SupervisorEvent>>,
+    dispatcher: Arc<Dispatcher>,
+    account_store: Arc<dyn AccountStore>,
+    player_store: Arc<dyn PlayerStore>,
+    bans: Arc<BanList>,
+    shutdown_countdown: Duration,
+}
+
+impl Default for NexusCore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NexusCore {
@@ -43,107 +104,217 @@ impl NexusCore {
         Self {
             sessions: Arc::default(),
             identifiers: Arc::default(),
+            addresses: Arc::default(),
+            command_channel: ChannelConfig::bounded(4096, OverflowPolicy::Block),
+            supervisor_events: None,
+            dispatcher: Arc::new(NexusCore::routes()),
+            account_store: Arc::new(InMemoryAccountStore::default()),
+            player_store: Arc::new(InMemoryPlayerStore::default()),
+            bans: Arc::new(BanList::default()),
+            shutdown_countdown: Duration::from_secs(10),
         }
     }
 
+    /// Which core handles each client message, and in which session state it is accepted.
+    pub(crate) fn routes() -> Dispatcher {
+        Dispatcher::new()
+            .register(ClientOpcode::Login, Handler::Nexus, Requirement::AwaitingLogin)
+            .register(ClientOpcode::Register, Handler::Nexus, Requirement::AwaitingLogin)
+            .register(ClientOpcode::MoveInput, Handler::Realm, Requirement::Playing)
+            .register(ClientOpcode::SnapshotAck, Handler::Realm, Requirement::Playing)
+    }
+
+    pub fn with_command_channel(mut self, commands: ChannelConfig) -> Self {
+        self.command_channel = commands;
+        self
+    }
+
+    /// Where accounts are looked up and created. Defaults to an in-memory store.
+    pub fn with_account_store(mut self, account_store: Arc<dyn AccountStore>) -> Self {
+        self.account_store = account_store;
+        self
+    }
+
+    /// Where player characters are saved between sessions. Defaults to an in-memory store.
+    pub fn with_player_store(mut self, player_store: Arc<dyn PlayerStore>) -> Self {
+        self.player_store = player_store;
+        self
+    }
+
+    /// Accounts refused at login. Defaults to an empty in-memory list.
+    pub fn with_ban_list(mut self, bans: Arc<BanList>) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    /// How long clients are warned before a `Shutdown` disconnects them.
+    pub fn with_shutdown_countdown(mut self, countdown: Duration) -> Self {
+        self.shutdown_countdown = countdown;
+        self
+    }
+
+    /// Lets the nexus reconcile sessions when the supervisor restarts one of its cores.
+    pub fn with_supervisor_events(mut self, events: Receiver<SupervisorEvent>) -> Self {
+        self.supervisor_events = Some(events);
+        self
+    }
+
     pub fn start(
         &mut self,
         mut networking_core: Core<NetCommand, NetEvent>,
         mut realm_core: Core<RealmCommand, RealmEvent>,
     ) -> Core<NexusCommand> {
         info!("Starting Nexus Core");
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx, rx) = channel::channel(self.command_channel);
+        let store_tx = writer::spawn(self.player_store.clone());
         let cancellation_token = CancellationToken::new();
         let net_event_handle = NexusCore::net_event_loop(
             tx.clone(),
+            realm_core.tx.clone(),
             networking_core.take_rx().unwrap(),
+            self.sessions.clone(),
+            self.addresses.clone(),
+            self.dispatcher.clone(),
             cancellation_token.clone(),
         );
         let realm_event_handle = NexusCore::realm_event_loop(
             tx.clone(),
+            networking_core.tx.clone(),
+            store_tx.clone(),
             realm_core.take_rx().unwrap(),
             cancellation_token.clone(),
         );
+        let supervisor_event_handle = self.supervisor_events.take().map(|events| {
+            NexusCore::supervisor_event_loop(tx.clone(), events, self.sessions.clone(), cancellation_token.clone())
+        });
         let handle = NexusCore::control_loop(
+            tx.clone(),
             rx,
+            self.account_store.clone(),
+            store_tx,
+            self.bans.clone(),
             self.sessions.clone(),
             self.identifiers.clone(),
+            self.addresses.clone(),
+            self.shutdown_countdown,
             cancellation_token.clone(),
             networking_core,
             net_event_handle,
             realm_core,
             realm_event_handle,
+            supervisor_event_handle,
         );
         Core::new(tx, handle)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn control_loop(
-        mut rx: tokio::sync::mpsc::UnboundedReceiver<NexusCommand>,
+        tx: Sender<NexusCommand>,
+        mut rx: Receiver<NexusCommand>,
+        account_store: Arc<dyn AccountStore>,
+        store_tx: Sender<StoreCommand>,
+        bans: Arc<BanList>,
         sessions: Arc<DashMap<ConnectionId, SessionState>>,
         identifiers: Arc<DashMap<Uuid, ConnectionId>>,
+        addresses: Arc<DashMap<ConnectionId, IpAddr>>,
+        shutdown_countdown: Duration,
         cancellation_token: CancellationToken,
         networking_core: Core<NetCommand, NetEvent>,
         net_event_handle: JoinHandle<()>,
         realm_core: Core<RealmCommand, RealmEvent>,
         realm_event_handle: JoinHandle<()>,
+        supervisor_event_handle: Option<JoinHandle<()>>,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
+            let monitor_handle = NexusCore::monitor_loop(
+                realm_core.tx.clone(),
+                networking_core.tx.clone(),
+                sessions.clone(),
+                cancellation_token.clone(),
+            );
+            let mut throttle = LoginThrottle::default();
+            let mut shutting_down = false;
             loop {
                 match rx.recv().await {
                     Some(command) => match command {
                         NexusCommand::Stop => {
                             info!("NexusCore: Stopping");
+                            if let Err(e) = networking_core.tx.send(NetCommand::StopAccepting).await {
+                                warn!("Failed to send NetCommand: [{e}]");
+                            }
+                            NexusCore::save_players(&realm_core, &store_tx).await;
+                            let _ = realm_core.stop(Some(RealmCommand::Stop)).await;
                             cancellation_token.cancel();
-                            let _ = networking_core.stop(Some(NetCommand::Stop));
+                            let _ = networking_core.stop(Some(NetCommand::Stop)).await;
                             let _ = net_event_handle.await;
-                            let _ = realm_core.stop(Some(RealmCommand::Stop));
                             let _ = realm_event_handle.await;
+                            let _ = monitor_handle.await;
+                            if let Some(handle) = supervisor_event_handle {
+                                let _ = handle.await;
+                            }
                             break;
                         }
-                        NexusCommand::RegisterConnection { connection_id } => {
-                            sessions.insert(connection_id, SessionState::AwaitingLogin);
+                        NexusCommand::Shutdown => {
+                            if shutting_down {
+                                continue;
+                            }
+                            shutting_down = true;
+                            info!("NexusCore: Shutting down in [{}s]", shutdown_countdown.as_secs());
+                            if let Err(e) = networking_core.tx.send(NetCommand::StopAccepting).await {
+                                warn!("Failed to send NetCommand: [{e}]");
+                            }
+                            tokio::spawn(NexusCore::count_down(tx.clone(), networking_core.tx.clone(), shutdown_countdown));
                         }
                         NexusCommand::UnregisterConnection { connection_id } => {
-                            info!("Unregistering connection [{connection_id:?}]");
-                            if let Some((_, session)) = sessions.remove(&connection_id) {
-                                if let SessionState::Playing {
-                                    entity_identifier,
-                                    account,
-                                    display_name,
-                                    current_map: _,
-                                } = session
-                                {
-                                    info!(
-                                        "Removing registered entity for [{account}][{display_name}]"
-                                    );
-                                    identifiers.remove(&entity_identifier);
-                                }
-                            }
+                            NexusCore::unregister_connection(connection_id, &sessions, &identifiers, &networking_core.tx, &realm_core.tx).await;
+                        }
+                        NexusCommand::PlayerDespawned { connection_id } => {
+                            sessions.remove_if(&connection_id, |_, session| matches!(session, SessionState::LoggingOut { .. }));
                         }
                         NexusCommand::RegisterPlayer {
                             entity_identifier,
                             account,
+                            display_name,
                             current_map,
                             connection_id,
                         } => {
-                            if let Some(mut session) = sessions.get_mut(&connection_id) {
+                            if let Some(mut session) = sessions
+                                .get_mut(&connection_id)
+                                .filter(|session| matches!(**session, SessionState::LoggingIn { .. }))
+                            {
                                 info!("Registered player [{account}]");
+                                let group = NexusCore::map_group(&current_map);
                                 *session = SessionState::Playing {
                                     entity_identifier,
                                     account,
-                                    display_name: "unknown".to_string(),
+                                    display_name,
                                     current_map,
                                 };
+                                drop(session);
                                 identifiers.insert(entity_identifier, connection_id);
+                                if let Err(e) = networking_core.tx.send(NetCommand::JoinGroup { group, connection: connection_id }).await {
+                                    warn!("Failed to send NetCommand: [{e}]");
+                                }
                             } else {
+                                // The connection went away while the player was spawning.
                                 warn!(
                                     "No registered session for player [{account}]"
                                 );
+                                if let Err(e) = realm_core.tx.send(RealmCommand::DespawnPlayer { connection_id, entity_identifier }).await {
+                                    warn!("Failed to send RealmCommand: [{e}]");
+                                    sessions.remove(&connection_id);
+                                }
                             }
                         }
                         NexusCommand::SetDisplayName { connection_id, display_name } => {
-                            if let Some(mut session) = sessions.get_mut(&connection_id) {
-                                session.set_display_name(display_name);
+                            let entity_identifier = sessions.get_mut(&connection_id).and_then(|mut session| {
+                                session.set_display_name(display_name.clone());
+                                session.get_entity_identifier().copied()
+                            });
+                            if let Some(entity_identifier) = entity_identifier
+                                && let Err(e) = realm_core.tx.send(RealmCommand::SetDisplayName { entity_identifier, display_name }).await
+                            {
+                                warn!("Failed to send RealmCommand: [{e}]");
                             }
                         }
                         NexusCommand::SetCurrentMap { entity_identifier, new_map } => {
@@ -153,13 +324,86 @@ impl NexusCore {
                                 &entity_identifier,
                             ) {
                                 Some(mut session) => {
+                                    let connection_id = *session.key();
+                                    let old_map = session.get_current_map().cloned();
+                                    let group = NexusCore::map_group(&new_map);
                                     session.set_current_map(new_map);
+                                    drop(session);
+                                    if let Some(old_map) = old_map {
+                                        NexusCore::leave_map_group(&networking_core.tx, connection_id, &old_map).await;
+                                    }
+                                    if let Err(e) = networking_core.tx.send(NetCommand::JoinGroup { group, connection: connection_id }).await {
+                                        warn!("Failed to send NetCommand: [{e}]");
+                                    }
                                 }
                                 None => {
                                     warn!("Unable to find session for entity [{entity_identifier}]");
                                 }
                             }
                         }
+                        NexusCommand::GetCurrentMap { account, reply } => {
+                            let current_map = sessions.iter().find_map(|session| {
+                                if session.get_account() == Some(&account) {
+                                    session.get_current_map().cloned()
+                                } else {
+                                    None
+                                }
+                            });
+                            reply.send(current_map);
+                        }
+                        NexusCommand::ClientMessage { connection_id, ip, message } => {
+                            NexusCore::handle_client_message(
+                                connection_id,
+                                ip,
+                                message,
+                                &tx,
+                                &sessions,
+                                &account_store,
+                                &store_tx,
+                                &bans,
+                                &throttle,
+                                &networking_core.tx,
+                            )
+                            .await;
+                        }
+                        NexusCommand::ClaimAccount { connection_id, account, reply } => {
+                            let taken = sessions.iter().any(|session| *session.key() != connection_id && session.get_account() == Some(&account));
+                            let claimed = !taken
+                                && match sessions.get_mut(&connection_id) {
+                                    Some(mut session) if matches!(*session, SessionState::Verifying) => {
+                                        *session = SessionState::LoggingIn { account };
+                                        true
+                                    }
+                                    _ => false,
+                                };
+                            reply.send(claimed);
+                        }
+                        NexusCommand::LoginVerified { connection_id, ip, account, result, record } => {
+                            NexusCore::finish_login(
+                                connection_id,
+                                ip,
+                                account,
+                                result,
+                                record,
+                                &sessions,
+                                &mut throttle,
+                                &networking_core.tx,
+                                &realm_core.tx,
+                            )
+                            .await;
+                        }
+                        NexusCommand::CoreRestarted { core, connections } => {
+                            NexusCore::reconcile_sessions(
+                                core,
+                                &connections,
+                                &sessions,
+                                &identifiers,
+                                &addresses,
+                                &networking_core.tx,
+                                &realm_core.tx,
+                            )
+                            .await;
+                        }
                     },
                     None => {
                         warn!("NexusCore: Closed channel");
@@ -171,8 +415,12 @@ impl NexusCore {
     }
 
     fn net_event_loop(
-        tx: tokio::sync::mpsc::UnboundedSender<NexusCommand>,
-        mut rx: tokio::sync::mpsc::UnboundedReceiver<NetEvent>,
+        tx: Sender<NexusCommand>,
+        realm_tx: Sender<RealmCommand>,
+        mut rx: Receiver<NetEvent>,
+        sessions: Arc<DashMap<ConnectionId, SessionState>>,
+        addresses: Arc<DashMap<ConnectionId, IpAddr>>,
+        dispatcher: Arc<Dispatcher>,
         cancellation_token: CancellationToken,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
@@ -185,19 +433,46 @@ impl NexusCore {
                     incoming = rx.recv() => {
                         match incoming {
                             Some(event) => match event {
-                                NetEvent::NewConnection { connection_id } => {
-                                    if let Err(_) = tx.send(NexusCommand::RegisterConnection { connection_id }) {
-                                        break;
-                                    }
+                                NetEvent::NewConnection { connection_id, address } => {
+                                    // Registered here rather than by the control loop, so the
+                                    // connection's first message cannot be dispatched before it.
+                                    sessions.insert(connection_id, SessionState::AwaitingLogin);
+                                    addresses.insert(connection_id, address.ip());
+                                }
+                                NetEvent::Kicked { connection_id, violation, violations } => {
+                                    info!("Connection [{connection_id:?}] kicked after [{violations}] violations: {violation}");
                                 }
                                 NetEvent::Disconnected { connection_id } => {
-                                    if let Err(_) = tx.send(NexusCommand::UnregisterConnection { connection_id }) {
+                                    addresses.remove(&connection_id);
+                                    if tx.send(NexusCommand::UnregisterConnection { connection_id }).await.is_err() {
                                         break;
                                     }
                                 }
                                 NetEvent::IncomingMessage { connection_id, message } => {
-                                    // TODO: dispatch messages
                                     debug!("Incoming message from [{connection_id:?}] [{message:?}]");
+                                    let dispatch = {
+                                        let session = sessions.get(&connection_id);
+                                        dispatcher.dispatch(session.as_deref(), &message)
+                                    };
+                                    match dispatch {
+                                        Dispatch::Nexus => {
+                                            // Sessions only exist for announced connections.
+                                            let Some(ip) = addresses.get(&connection_id).map(|ip| *ip) else {
+                                                continue;
+                                            };
+                                            if tx.send(NexusCommand::ClientMessage { connection_id, ip, message }).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        Dispatch::Realm { entity_identifier } => {
+                                            if let Err(e) = realm_tx.send(RealmCommand::ClientMessage { connection_id, entity_identifier, message }).await {
+                                                warn!("Failed to send RealmCommand: [{e}]");
+                                            }
+                                        }
+                                        Dispatch::Rejected(rejection) => {
+                                            warn!("Rejected message from [{connection_id:?}]: {rejection}");
+                                        }
+                                    }
                                 }
                             }
                             None => {
@@ -212,8 +487,10 @@ impl NexusCore {
     }
 
     fn realm_event_loop(
-        tx: tokio::sync::mpsc::UnboundedSender<NexusCommand>,
-        mut rx: tokio::sync::mpsc::UnboundedReceiver<RealmEvent>,
+        tx: Sender<NexusCommand>,
+        net_tx: Sender<NetCommand>,
+        store_tx: Sender<StoreCommand>,
+        mut rx: Receiver<RealmEvent>,
         cancellation_token: CancellationToken,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
@@ -226,12 +503,34 @@ impl NexusCore {
                     incoming = rx.recv() => {
                         match incoming {
                             Some(event) => match event {
-                                RealmEvent::PlayerSpawned { connection_id, account, current_map, entity_identifier } => {
-                                    if let Err(e) = tx.send(NexusCommand::RegisterPlayer { connection_id, account, current_map, entity_identifier }) {
+                                RealmEvent::PlayerSpawned { connection_id, account, display_name, current_map, entity_identifier } => {
+                                    if let Err(e) = tx.send(NexusCommand::RegisterPlayer { connection_id, account, display_name, current_map, entity_identifier }).await {
+                                        warn!("Failed to send NexusCommand: [{e}]");
+                                        break;
+                                    }
+                                }
+                                // Comes after the player's final save, which was queued above.
+                                RealmEvent::PlayerDespawned { connection_id } => {
+                                    if let Err(e) = tx.send(NexusCommand::PlayerDespawned { connection_id }).await {
                                         warn!("Failed to send NexusCommand: [{e}]");
                                         break;
                                     }
                                 }
+                                RealmEvent::Send { connection_id, message } => {
+                                    if let Err(e) = net_tx.send(NetCommand::Send { connection: connection_id, message }).await {
+                                        warn!("Failed to send NetCommand: [{e}]");
+                                    }
+                                }
+                                RealmEvent::Multicast { connection_ids, message } => {
+                                    if let Err(e) = net_tx.send(NetCommand::Multicast { connections: connection_ids, message }).await {
+                                        warn!("Failed to send NetCommand: [{e}]");
+                                    }
+                                }
+                                RealmEvent::SavePlayer { record } => {
+                                    if let Err(e) = store_tx.send(StoreCommand::Save { record }).await {
+                                        error!("Failed to queue player save: [{e}]");
+                                    }
+                                }
                             }
                             None => {
                                 warn!("Realm event channel closed");
@@ -244,6 +543,401 @@ impl NexusCore {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_client_message(
+        connection_id: ConnectionId,
+        ip: IpAddr,
+        message: FromClient,
+        tx: &Sender<NexusCommand>,
+        sessions: &DashMap<ConnectionId, SessionState>,
+        account_store: &Arc<dyn AccountStore>,
+        store_tx: &Sender<StoreCommand>,
+        bans: &Arc<BanList>,
+        throttle: &LoginThrottle,
+        net_tx: &Sender<NetCommand>,
+    ) {
+        let (account, password, register) = match message {
+            FromClient::Handshake(_) => {
+                warn!("Handshake from [{connection_id:?}] should have been handled by networking");
+                return;
+            }
+            FromClient::MoveInput(_) | FromClient::SnapshotAck(_) => {
+                warn!("Realm message from [{connection_id:?}] should have been routed to the realm");
+                return;
+            }
+            FromClient::Ping(_) | FromClient::Pong(_) => {
+                warn!("Heartbeat from [{connection_id:?}] should have been handled by networking");
+                return;
+            }
+            FromClient::Login(login) => (login.account, login.password, false),
+            FromClient::Register(register) => (register.account, register.password, true),
+        };
+        let account = account::normalize_account(&account);
+        if let Some(retry_after) = throttle.retry_after(ip, &account, Instant::now()) {
+            let retry_after_secs = retry_after.as_secs().max(1) as u32;
+            NexusCore::send_login_result(net_tx, connection_id, LoginResult::Throttled { retry_after_secs }).await;
+            return;
+        }
+        match sessions.get_mut(&connection_id) {
+            Some(mut session) => *session = SessionState::Verifying,
+            None => return,
+        }
+        // Hashing is deliberately slow, keep it off the async workers.
+        let tx = tx.clone();
+        let account_store = account_store.clone();
+        let store_tx = store_tx.clone();
+        let bans = bans.clone();
+        tokio::spawn(async move {
+            let check_account = account.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let result = if register {
+                    account::register(account_store.as_ref(), &check_account, &password)
+                } else {
+                    account::verify(account_store.as_ref(), &check_account, &password)
+                };
+                if result != LoginResult::Success {
+                    return result;
+                }
+                // Checked once the password matched, so the ban list cannot be probed.
+                match bans.account_ban(&check_account, SystemTime::now()) {
+                    Some(ban) => LoginResult::Banned { reason: ban.reason, expires_at: ban.expires_at },
+                    None => result,
+                }
+            })
+            .await
+            .unwrap_or(LoginResult::ServerError);
+            // Also checked once the password matched, so whether the account is online cannot be
+            // probed either.
+            let result = match result {
+                LoginResult::Success => {
+                    let claim = |reply| NexusCommand::ClaimAccount { connection_id, account: account.clone(), reply };
+                    match request(&tx, claim, CORE_REQUEST_TIMEOUT).await {
+                        Ok(true) => result,
+                        Ok(false) => LoginResult::AlreadyLoggedIn,
+                        Err(e) => {
+                            error!("Failed to claim account [{account}]: {e}");
+                            LoginResult::ServerError
+                        }
+                    }
+                }
+                result => result,
+            };
+            let (result, record) = match result {
+                // The account is held until its last session's final save was queued, so this is
+                // queued behind it and cannot read a character from before it.
+                LoginResult::Success => {
+                    let load = |reply| StoreCommand::Load { account: account.clone(), reply };
+                    match request(&store_tx, load, STORE_REQUEST_TIMEOUT).await {
+                        Ok(Ok(record)) => (result, record),
+                        // Refuse the login rather than spawning a blank character over the saved one.
+                        Ok(Err(e)) => {
+                            error!("Failed to load player [{account}]: {e}");
+                            (LoginResult::ServerError, None)
+                        }
+                        Err(e) => {
+                            error!("Failed to load player [{account}]: {e}");
+                            (LoginResult::ServerError, None)
+                        }
+                    }
+                }
+                result => (result, None),
+            };
+            if let Err(e) = tx.send(NexusCommand::LoginVerified { connection_id, ip, account, result, record }).await {
+                warn!("Failed to send NexusCommand: [{e}]");
+            }
+        });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn finish_login(
+        connection_id: ConnectionId,
+        ip: IpAddr,
+        account: String,
+        result: LoginResult,
+        record: Option<PlayerRecord>,
+        sessions: &DashMap<ConnectionId, SessionState>,
+        throttle: &mut LoginThrottle,
+        net_tx: &Sender<NetCommand>,
+        realm_tx: &Sender<RealmCommand>,
+    ) {
+        let mut ban = None;
+        match &result {
+            LoginResult::Success => throttle.record_success(ip, &account),
+            LoginResult::InvalidCredentials => {
+                info!("Failed login for [{account}] from [{ip}] ({connection_id:?})");
+                throttle.record_failure(ip, &account, Instant::now());
+            }
+            LoginResult::Banned { reason, expires_at } => {
+                info!("Refusing banned account [{account}] from [{connection_id:?}]");
+                ban = Some(DisconnectReason::Banned { reason: reason.clone(), expires_at: *expires_at });
+            }
+            _ => {}
+        }
+        {
+            let session = sessions.get_mut(&connection_id).filter(|session| !matches!(**session, SessionState::LoggingOut { .. }));
+            let Some(mut session) = session else {
+                info!("Connection [{connection_id:?}] left before logging in as [{account}]");
+                sessions.remove(&connection_id);
+                return;
+            };
+            if result != LoginResult::Success {
+                *session = SessionState::AwaitingLogin;
+            }
+        }
+        if result == LoginResult::Success {
+            info!("[{account}] logged in from [{connection_id:?}]");
+            if let Err(e) = realm_tx.send(RealmCommand::SpawnPlayer { connection_id, account, record }).await {
+                warn!("Failed to send RealmCommand: [{e}]");
+            }
+        }
+        NexusCore::send_login_result(net_tx, connection_id, result).await;
+        if let Some(reason) = ban
+            && let Err(e) = net_tx.send(NetCommand::Disconnect { connection: connection_id, reason }).await
+        {
+            warn!("Failed to send NetCommand: [{e}]");
+        }
+    }
+
+    async fn send_login_result(net_tx: &Sender<NetCommand>, connection: ConnectionId, result: LoginResult) {
+        let message = FromServer::LoginResult(result);
+        if let Err(e) = net_tx.send(NetCommand::Send { connection, message }).await {
+            warn!("Failed to send NetCommand: [{e}]");
+        }
+    }
+
+    /// Logs the realm's tick timing and the clients' round trip times every `MONITOR_INTERVAL`.
+    fn monitor_loop(
+        realm_tx: Sender<RealmCommand>,
+        net_tx: Sender<NetCommand>,
+        sessions: Arc<DashMap<ConnectionId, SessionState>>,
+        cancellation_token: CancellationToken,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + MONITOR_INTERVAL, MONITOR_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = interval.tick() => {
+                        match request(&realm_tx, |reply| RealmCommand::GetTickStats { reply }, CORE_REQUEST_TIMEOUT).await {
+                            Ok(stats) => info!(
+                                "Realm ran [{}] ticks: mean [{:?}], p99 [{:?}], [{}] overruns, [{}] skipped",
+                                stats.ticks, stats.mean, stats.p99, stats.overruns, stats.skipped
+                            ),
+                            Err(e) => warn!("Failed to get realm tick stats: {e}"),
+                        }
+                        NexusCore::log_round_trips(&net_tx, &sessions).await;
+                    }
+                }
+            }
+        })
+    }
+
+    async fn log_round_trips(net_tx: &Sender<NetCommand>, sessions: &DashMap<ConnectionId, SessionState>) {
+        let connections: Vec<ConnectionId> = sessions.iter().map(|session| *session.key()).collect();
+        let mut rtts = Vec::with_capacity(connections.len());
+        for connection in connections {
+            match request(net_tx, |reply| NetCommand::GetRtt { connection, reply }, CORE_REQUEST_TIMEOUT).await {
+                Ok(Some(rtt)) => rtts.push(rtt),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Failed to get round trip times: {e}");
+                    return;
+                }
+            }
+        }
+        let Some(max) = rtts.iter().max() else {
+            return;
+        };
+        let mean = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        info!("Round trip times of [{}] clients: mean [{mean:?}], max [{max:?}]", rtts.len());
+    }
+
+    fn supervisor_event_loop(
+        tx: Sender<NexusCommand>,
+        mut rx: Receiver<SupervisorEvent>,
+        sessions: Arc<DashMap<ConnectionId, SessionState>>,
+        cancellation_token: CancellationToken,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => {
+                        info!("Supervisor event listener closing");
+                        break;
+                    },
+                    incoming = rx.recv() => {
+                        match incoming {
+                            Some(SupervisorEvent::Restarted { core, restarts }) => {
+                                warn!("Core [{core}] crashed and was restarted ({restarts} recent restarts)");
+                                // Taken before the restarted core's events can register new sessions.
+                                let connections = sessions.iter().map(|session| *session.key()).collect();
+                                if tx.send(NexusCommand::CoreRestarted { core, connections }).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Some(SupervisorEvent::GaveUp { core }) => {
+                                error!("Core [{core}] keeps crashing, shutting the server down");
+                                let _ = tx.send(NexusCommand::Stop).await;
+                                break;
+                            }
+                            Some(SupervisorEvent::Exited { core }) => {
+                                info!("Core [{core}] exited");
+                            }
+                            None => {
+                                warn!("Supervisor event channel closed");
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Warns every client as the shutdown approaches, then stops the nexus.
+    async fn count_down(tx: Sender<NexusCommand>, net_tx: Sender<NetCommand>, countdown: Duration) {
+        let deadline = tokio::time::Instant::now() + countdown;
+        let total = countdown.as_secs() as u32;
+        for seconds_remaining in (1..=total).rev() {
+            tokio::time::sleep_until(deadline - Duration::from_secs(seconds_remaining.into())).await;
+            // Every ten seconds, then every second for the last five.
+            if seconds_remaining == total || seconds_remaining <= 5 || seconds_remaining % 10 == 0 {
+                let message = FromServer::ShutdownNotice(ShutdownNotice { seconds_remaining });
+                if let Err(e) = net_tx.send(NetCommand::Broadcast { message }).await {
+                    warn!("Failed to send NetCommand: [{e}]");
+                }
+            }
+        }
+        tokio::time::sleep_until(deadline).await;
+        if let Err(e) = tx.send(NexusCommand::Stop).await {
+            warn!("Failed to send NexusCommand: [{e}]");
+        }
+    }
+
+    /// Saves the current state of every player in the realm, waiting for the store to finish.
+    async fn save_players(realm_core: &Core<RealmCommand, RealmEvent>, store_tx: &Sender<StoreCommand>) {
+        let records = match realm_core.request(|reply| RealmCommand::GetPlayerRecords { reply }, CORE_REQUEST_TIMEOUT).await {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Failed to collect players to save: {e}");
+                return;
+            }
+        };
+        let total = records.len();
+        match request(store_tx, |reply| StoreCommand::SaveAll { records, reply }, STORE_REQUEST_TIMEOUT).await {
+            Ok(saved) => info!("Saved [{saved}/{total}] players"),
+            Err(e) => error!("Failed to save players: {e}"),
+        }
+    }
+
+    /// Forgets a closed connection. A player it had is despawned, which saves it, and its
+    /// account stays taken until the realm reports the despawn.
+    async fn unregister_connection(
+        connection_id: ConnectionId,
+        sessions: &DashMap<ConnectionId, SessionState>,
+        identifiers: &DashMap<Uuid, ConnectionId>,
+        net_tx: &Sender<NetCommand>,
+        realm_tx: &Sender<RealmCommand>,
+    ) {
+        info!("Unregistering connection [{connection_id:?}]");
+        let Some((_, session)) = sessions.remove(&connection_id) else {
+            return;
+        };
+        match session {
+            SessionState::Playing {
+                entity_identifier,
+                account,
+                display_name,
+                current_map,
+            } => {
+                info!(
+                    "Removing registered entity for [{account}][{display_name}]"
+                );
+                identifiers.remove(&entity_identifier);
+                sessions.insert(connection_id, SessionState::LoggingOut { account });
+                NexusCore::leave_map_group(net_tx, connection_id, &current_map).await;
+                if let Err(e) = realm_tx.send(RealmCommand::DespawnPlayer { connection_id, entity_identifier }).await {
+                    warn!("Failed to send RealmCommand: [{e}]");
+                    sessions.remove(&connection_id);
+                }
+            }
+            // The login may still spawn a player, `RegisterPlayer` despawns it.
+            SessionState::LoggingIn { account } => {
+                sessions.insert(connection_id, SessionState::LoggingOut { account });
+            }
+            SessionState::AwaitingLogin | SessionState::Verifying | SessionState::LoggingOut { .. } => {}
+        }
+    }
+
+    /// Brings the sessions back in line with a core that lost its state in a restart.
+    /// `connections` are the ones that had a session when the restart was reported.
+    async fn reconcile_sessions(
+        core: &str,
+        connections: &[ConnectionId],
+        sessions: &DashMap<ConnectionId, SessionState>,
+        identifiers: &DashMap<Uuid, ConnectionId>,
+        addresses: &DashMap<ConnectionId, IpAddr>,
+        net_tx: &Sender<NetCommand>,
+        realm_tx: &Sender<RealmCommand>,
+    ) {
+        match core {
+            networking_core::CORE_NAME => {
+                // Their sockets went down with the old core, the restarted one may already have
+                // accepted new ones. Their players are still in the world, despawning them saves
+                // their progress.
+                warn!("Dropping [{}] sessions after networking restart", connections.len());
+                for &connection_id in connections {
+                    addresses.remove(&connection_id);
+                    NexusCore::unregister_connection(connection_id, sessions, identifiers, net_tx, realm_tx).await;
+                }
+            }
+            realm_core::CORE_NAME => {
+                // Connections survive, but their entities are gone with the old world. Their
+                // clients are told so and disconnected, they can log in again. Despawns went down
+                // with it too and will not be answered.
+                sessions.retain(|_, session| !matches!(session, SessionState::LoggingOut { .. }));
+                let mut lost = Vec::new();
+                for mut session in sessions.iter_mut() {
+                    // Still checking credentials, the player spawns into the new world.
+                    if matches!(*session, SessionState::Verifying) {
+                        continue;
+                    }
+                    if let Some(current_map) = session.get_current_map() {
+                        lost.push((*session.key(), Some(NexusCore::map_group(current_map))));
+                    } else if matches!(*session, SessionState::LoggingIn { .. }) {
+                        lost.push((*session.key(), None));
+                    }
+                    *session = SessionState::AwaitingLogin;
+                }
+                identifiers.clear();
+                warn!("Disconnecting [{}] logged in sessions after realm restart", lost.len());
+                for (connection, group) in lost {
+                    if let Some(group) = group
+                        && let Err(e) = net_tx.send(NetCommand::LeaveGroup { group, connection }).await
+                    {
+                        warn!("Failed to send NetCommand: [{e}]");
+                    }
+                    if let Err(e) = net_tx.send(NetCommand::Disconnect { connection, reason: DisconnectReason::SessionLost }).await {
+                        warn!("Failed to send NetCommand: [{e}]");
+                    }
+                }
+            }
+            other => warn!("No session reconciliation for core [{other}]"),
+        }
+    }
+
+    /// Group of every connection playing on `map`.
+    pub fn map_group(map: &str) -> String {
+        format!("map:{map}")
+    }
+
+    async fn leave_map_group(net_tx: &Sender<NetCommand>, connection: ConnectionId, map: &str) {
+        let group = NexusCore::map_group(map);
+        if let Err(e) = net_tx.send(NetCommand::LeaveGroup { group, connection }).await {
+            warn!("Failed to send NetCommand: [{e}]");
+        }
+    }
+
     fn get_session_for_identifier<'a>(
         sessions: &'a DashMap<ConnectionId, SessionState>,
         identifiers: &DashMap<Uuid, ConnectionId>,
@@ -253,3 +947,179 @@ impl NexusCore {
         sessions.get_mut(connection_id.value())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    fn playing(entity_identifier: Uuid) -> SessionState {
+        SessionState::Playing {
+            entity_identifier,
+            account: "alice".to_string(),
+            display_name: "Alice".to_string(),
+            current_map: "map".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reconciles_sessions_with_restarted_cores() {
+        let counter = AtomicU64::new(0);
+        let (player, waiting) = (ConnectionId::next(&counter), ConnectionId::next(&counter));
+        let entity_identifier = Uuid::new_v4();
+        let sessions = DashMap::from_iter([(player, playing(entity_identifier)), (waiting, SessionState::AwaitingLogin)]);
+        let identifiers = DashMap::from_iter([(entity_identifier, player)]);
+        let address: IpAddr = "127.0.0.1".parse().unwrap();
+        let addresses = DashMap::from_iter([(player, address), (waiting, address)]);
+        let (net_tx, mut net_rx) = channel::unbounded_channel();
+        let (realm_tx, mut realm_rx) = channel::unbounded_channel();
+
+        // The world lost the player: its client is dropped so it can log in again.
+        let connections = [player, waiting];
+        NexusCore::reconcile_sessions(realm_core::CORE_NAME, &connections, &sessions, &identifiers, &addresses, &net_tx, &realm_tx).await;
+        assert!(matches!(net_rx.try_recv(), Some(NetCommand::LeaveGroup { connection, .. }) if connection == player));
+        assert!(matches!(
+            net_rx.try_recv(),
+            Some(NetCommand::Disconnect { connection, reason: DisconnectReason::SessionLost }) if connection == player
+        ));
+        assert!(net_rx.try_recv().is_none());
+        assert!(matches!(*sessions.get(&player).unwrap(), SessionState::AwaitingLogin));
+
+        // The sockets are gone: the player is despawned, which saves it. A connection the
+        // restarted core accepted meanwhile is kept.
+        sessions.insert(player, playing(entity_identifier));
+        identifiers.insert(entity_identifier, player);
+        let accepted = ConnectionId::next(&counter);
+        sessions.insert(accepted, SessionState::AwaitingLogin);
+        addresses.insert(accepted, address);
+        NexusCore::reconcile_sessions(networking_core::CORE_NAME, &connections, &sessions, &identifiers, &addresses, &net_tx, &realm_tx).await;
+        assert!(matches!(realm_rx.try_recv(), Some(RealmCommand::DespawnPlayer { entity_identifier: despawned, .. }) if despawned == entity_identifier));
+        assert!(matches!(*sessions.get(&player).unwrap(), SessionState::LoggingOut { .. }));
+        assert!(!sessions.contains_key(&waiting));
+        assert!(identifiers.is_empty());
+        assert!(matches!(*sessions.get(&accepted).unwrap(), SessionState::AwaitingLogin));
+        assert_eq!(addresses.len(), 1);
+        assert!(addresses.contains_key(&accepted));
+    }
+
+    /// Stand-in for a core: its commands come out of the receiver, its events go into the sender.
+    fn fake_core<Command, Event>() -> (Core<Command, Event>, Receiver<Command>, Sender<Event>) {
+        let (tx, commands) = channel::unbounded_channel();
+        let (events, rx) = channel::unbounded_channel();
+        (Core::new(tx, tokio::spawn(async {})).with_events(rx), commands, events)
+    }
+
+    async fn login_result(net_rx: &mut Receiver<NetCommand>) -> LoginResult {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(30), net_rx.recv()).await.unwrap() {
+                Some(NetCommand::Send { message: FromServer::LoginResult(result), .. }) => return result,
+                Some(_) => {}
+                None => panic!("Nexus stopped"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_the_account_until_its_player_is_saved() {
+        let account_store = Arc::new(InMemoryAccountStore::default());
+        assert_eq!(account::register(account_store.as_ref(), "alice", "secret"), LoginResult::Success);
+        let (networking, mut net_rx, net_event_tx) = fake_core();
+        let (realm, mut realm_rx, realm_event_tx) = fake_core();
+        let _nexus = NexusCore::new().with_account_store(account_store).start(networking, realm);
+
+        let counter = AtomicU64::new(0);
+        let login = async |connection_id, password: &str| {
+            let message = FromClient::Login(shared::client_messages::Login { account: "alice".to_string(), password: password.to_string() });
+            net_event_tx.send(NetEvent::IncomingMessage { connection_id, message }).await.unwrap();
+        };
+        let first = ConnectionId::next(&counter);
+        net_event_tx.send(NetEvent::NewConnection { connection_id: first, address: "127.0.0.1:4000".parse().unwrap() }).await.unwrap();
+        login(first, "secret").await;
+        assert!(matches!(realm_rx.recv().await, Some(RealmCommand::SpawnPlayer { record: None, .. })));
+        let entity_identifier = Uuid::new_v4();
+        let spawned = RealmEvent::PlayerSpawned {
+            connection_id: first,
+            account: "alice".to_string(),
+            display_name: "alice".to_string(),
+            current_map: "map".to_string(),
+            entity_identifier,
+        };
+        realm_event_tx.send(spawned).await.unwrap();
+        assert_eq!(login_result(&mut net_rx).await, LoginResult::Success);
+
+        // Without the password, whether the account is online stays hidden.
+        let second = ConnectionId::next(&counter);
+        net_event_tx.send(NetEvent::NewConnection { connection_id: second, address: "127.0.0.1:4001".parse().unwrap() }).await.unwrap();
+        login(second, "guessed").await;
+        assert_eq!(login_result(&mut net_rx).await, LoginResult::InvalidCredentials);
+
+        // The realm has not saved the player yet when they come back.
+        net_event_tx.send(NetEvent::Disconnected { connection_id: first }).await.unwrap();
+        assert!(matches!(realm_rx.recv().await, Some(RealmCommand::DespawnPlayer { connection_id, .. }) if connection_id == first));
+        login(second, "secret").await;
+        assert_eq!(login_result(&mut net_rx).await, LoginResult::AlreadyLoggedIn);
+
+        let record = PlayerRecord {
+            account: "alice".to_string(),
+            display_name: "alice".to_string(),
+            current_map: "map".to_string(),
+            x: 40.0,
+            y: 24.0,
+            inventory: Vec::new(),
+        };
+        realm_event_tx.send(RealmEvent::SavePlayer { record: record.clone() }).await.unwrap();
+        realm_event_tx.send(RealmEvent::PlayerDespawned { connection_id: first }).await.unwrap();
+        // Freed once the nexus hears of the despawn, the client retries until then.
+        let result = loop {
+            login(second, "secret").await;
+            let result = login_result(&mut net_rx).await;
+            if result != LoginResult::AlreadyLoggedIn {
+                break result;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(result, LoginResult::Success);
+        let Some(RealmCommand::SpawnPlayer { connection_id, record: loaded, .. }) = realm_rx.recv().await else {
+            panic!("Expected the player to spawn");
+        };
+        assert_eq!(connection_id, second);
+        assert_eq!(loaded, Some(record));
+    }
+
+    #[tokio::test]
+    async fn answers_which_map_an_account_plays_on() {
+        let mut nexus = NexusCore::new();
+        nexus.sessions.insert(ConnectionId::next(&AtomicU64::new(0)), playing(Uuid::new_v4()));
+        let (networking, _net_rx, _net_event_tx) = fake_core();
+        let (realm, _realm_rx, _realm_event_tx) = fake_core();
+        let nexus = nexus.start(networking, realm);
+
+        let current_map = |account: &str| {
+            let account = account.to_string();
+            nexus.request(|reply| NexusCommand::GetCurrentMap { account, reply }, Duration::from_secs(1))
+        };
+        assert_eq!(current_map("alice").await, Ok(Some("map".to_string())));
+        assert_eq!(current_map("bob").await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn dispatches_a_login_sent_right_after_connecting() {
+        let (tx, mut rx) = channel::unbounded_channel();
+        let (realm_tx, _realm_rx) = channel::unbounded_channel();
+        let (event_tx, event_rx) = channel::unbounded_channel();
+        let sessions = Arc::new(DashMap::new());
+        let token = CancellationToken::new();
+        let handle = NexusCore::net_event_loop(tx, realm_tx, event_rx, sessions.clone(), Arc::default(), Arc::new(NexusCore::routes()), token.clone());
+
+        let connection_id = ConnectionId::next(&AtomicU64::new(0));
+        let login = FromClient::Login(shared::client_messages::Login { account: "alice".to_string(), password: "secret".to_string() });
+        event_tx.send(NetEvent::NewConnection { connection_id, address: "127.0.0.1:4000".parse().unwrap() }).await.unwrap();
+        event_tx.send(NetEvent::IncomingMessage { connection_id, message: login }).await.unwrap();
+
+        let command = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(command, Some(NexusCommand::ClientMessage { message: FromClient::Login(_), .. })));
+        token.cancel();
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::persistence::{PersistenceError, PlayerRecord, PlayerStore};
+
+/// Player store that lives only as long as the process. Meant for tests and local runs.
+#[derive(Default)]
+pub struct InMemoryPlayerStore {
+    players: Mutex<HashMap<String, PlayerRecord>>,
+}
+
+impl PlayerStore for InMemoryPlayerStore {
+    fn load(&self, account: &str) -> Result<Option<PlayerRecord>, PersistenceError> {
+        Ok(self.players.lock().unwrap().get(account).cloned())
+    }
+
+    fn save(&self, record: &PlayerRecord) -> Result<(), PersistenceError> {
+        self.players.lock().unwrap().insert(record.account.clone(), record.clone());
+        Ok(())
+    }
+}
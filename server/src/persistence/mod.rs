@@ -0,0 +1,66 @@
+pub mod memory;
+pub mod redb;
+pub mod writer;
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::realm::ecs::components::ItemStack;
+
+/// Version of the on-disk layout. Bump it and add a migration whenever a stored type changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Everything about a player character that outlives a session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerRecord {
+    pub account: String,
+    pub display_name: String,
+    pub current_map: String,
+    pub x: f32,
+    pub y: f32,
+    pub inventory: Vec<ItemStack>,
+}
+
+#[derive(Debug)]
+pub enum PersistenceError {
+    Database(::redb::Error),
+    Corrupt(serde_json::Error),
+    /// The store was written by a newer server, or its schema version is not one there ever was.
+    UnsupportedSchema {
+        found: u32,
+        /// Oldest version this server can migrate from.
+        oldest: u32,
+        /// Version this server writes.
+        newest: u32,
+    },
+}
+
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Database(err) => write!(f, "player store error: {err}"),
+            Self::Corrupt(err) => write!(f, "player record is corrupt: {err}"),
+            Self::UnsupportedSchema { found, oldest, newest } => write!(
+                f,
+                "player store has schema version {found}, this server supports {oldest} to {newest}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PersistenceError {}
+
+impl<E: Into<::redb::Error>> From<E> for PersistenceError {
+    fn from(err: E) -> Self {
+        Self::Database(err.into())
+    }
+}
+
+/// Backend holding player characters. Calls may block on disk, so the nexus only uses it from
+/// blocking tasks.
+pub trait PlayerStore: Send + Sync {
+    fn load(&self, account: &str) -> Result<Option<PlayerRecord>, PersistenceError>;
+
+    fn save(&self, record: &PlayerRecord) -> Result<(), PersistenceError>;
+}
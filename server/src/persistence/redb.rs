@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition, WriteTransaction};
+
+use crate::persistence::{PersistenceError, PlayerRecord, PlayerStore, SCHEMA_VERSION};
+
+const META: TableDefinition<&str, u32> = TableDefinition::new("meta");
+const PLAYERS: TableDefinition<&str, &[u8]> = TableDefinition::new("players");
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+type Migration = fn(&WriteTransaction) -> Result<(), PersistenceError>;
+
+/// Oldest schema version `MIGRATIONS` can upgrade from.
+const OLDEST_SCHEMA_VERSION: u32 = 1;
+/// `MIGRATIONS[n]` upgrades a store from schema version `OLDEST_SCHEMA_VERSION + n` to the one
+/// after it.
+const MIGRATIONS: &[Migration] = &[];
+
+/// Player store backed by an embedded redb database file.
+pub struct RedbPlayerStore {
+    db: Database,
+}
+
+impl RedbPlayerStore {
+    /// Opens or creates the database at `path`, migrating it to the current schema.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PersistenceError> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        {
+            let mut meta = txn.open_table(META)?;
+            let found = meta.get(SCHEMA_VERSION_KEY)?.map(|version| version.value());
+            match found {
+                None => {
+                    meta.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION)?;
+                }
+                Some(found) if !(OLDEST_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&found) => {
+                    return Err(PersistenceError::UnsupportedSchema {
+                        found,
+                        oldest: OLDEST_SCHEMA_VERSION,
+                        newest: SCHEMA_VERSION,
+                    });
+                }
+                Some(found) => {
+                    for version in found..SCHEMA_VERSION {
+                        info!("Migrating player store from schema [{version}] to [{}]", version + 1);
+                        MIGRATIONS[(version - OLDEST_SCHEMA_VERSION) as usize](&txn)?;
+                    }
+                    meta.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION)?;
+                }
+            }
+            txn.open_table(PLAYERS)?;
+        }
+        txn.commit()?;
+        Ok(Self { db })
+    }
+}
+
+impl PlayerStore for RedbPlayerStore {
+    fn load(&self, account: &str) -> Result<Option<PlayerRecord>, PersistenceError> {
+        let txn = self.db.begin_read()?;
+        let players = txn.open_table(PLAYERS)?;
+        match players.get(account)? {
+            Some(record) => serde_json::from_slice(record.value())
+                .map(Some)
+                .map_err(PersistenceError::Corrupt),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, record: &PlayerRecord) -> Result<(), PersistenceError> {
+        let contents = serde_json::to_vec(record).map_err(PersistenceError::Corrupt)?;
+        let txn = self.db.begin_write()?;
+        {
+            let mut players = txn.open_table(PLAYERS)?;
+            players.insert(record.account.as_str(), contents.as_slice())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::realm::ecs::components::ItemStack;
+
+    #[test]
+    fn saves_and_loads_across_reopen() {
+        let path = env::temp_dir().join(format!("players-{}.redb", Uuid::new_v4()));
+        let record = PlayerRecord {
+            account: "graal".to_string(),
+            display_name: "Graal".to_string(),
+            current_map: "offlinetutorial".to_string(),
+            x: 12.0,
+            y: 34.5,
+            inventory: vec![ItemStack { item: "bomb".to_string(), count: 5 }],
+        };
+        {
+            let store = RedbPlayerStore::open(&path).unwrap();
+            assert_eq!(store.load("graal").unwrap(), None);
+            store.save(&record).unwrap();
+        }
+        let store = RedbPlayerStore::open(&path).unwrap();
+        assert_eq!(store.load("graal").unwrap(), Some(record));
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_unknown_schemas() {
+        for version in [OLDEST_SCHEMA_VERSION - 1, SCHEMA_VERSION + 1] {
+            let path = env::temp_dir().join(format!("players-{}.redb", Uuid::new_v4()));
+            {
+                let db = Database::create(&path).unwrap();
+                let txn = db.begin_write().unwrap();
+                txn.open_table(META).unwrap().insert(SCHEMA_VERSION_KEY, version).unwrap();
+                txn.commit().unwrap();
+            }
+            assert!(matches!(
+                RedbPlayerStore::open(&path),
+                Err(PersistenceError::UnsupportedSchema { found, .. }) if found == version
+            ));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
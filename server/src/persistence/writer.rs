@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use shared::{channel::{self, Receiver, Sender}, core::Reply};
+
+use crate::persistence::{PersistenceError, PlayerRecord, PlayerStore};
+
+pub enum StoreCommand {
+    Save {
+        record: PlayerRecord,
+    },
+    /// Saves every record, answering how many of them were saved.
+    SaveAll {
+        records: Vec<PlayerRecord>,
+        reply: Reply<usize>,
+    },
+    Load {
+        account: String,
+        reply: Reply<Result<Option<PlayerRecord>, PersistenceError>>,
+    },
+}
+
+/// Starts the task every access to `store` goes through. Commands run one at a time in the order
+/// they were sent, so two saves of a player cannot land out of order and a load sees every save
+/// queued before it. The task ends once every sender is dropped.
+pub fn spawn(store: Arc<dyn PlayerStore>) -> Sender<StoreCommand> {
+    let (tx, rx) = channel::unbounded_channel();
+    tokio::spawn(run(store, rx));
+    tx
+}
+
+async fn run(store: Arc<dyn PlayerStore>, mut rx: Receiver<StoreCommand>) {
+    while let Some(command) = rx.recv().await {
+        let store = store.clone();
+        // Awaited before the next command is taken, that is what keeps them in order.
+        if let Err(e) = tokio::task::spawn_blocking(move || handle(store.as_ref(), command)).await {
+            error!("Player store task failed: {e}");
+        }
+    }
+}
+
+fn handle(store: &dyn PlayerStore, command: StoreCommand) {
+    match command {
+        StoreCommand::Save { record } => {
+            if let Err(e) = store.save(&record) {
+                error!("Failed to save player [{}]: {e}", record.account);
+            }
+        }
+        StoreCommand::SaveAll { records, reply } => {
+            let saved = records
+                .iter()
+                .filter(|record| match store.save(record) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Failed to save player [{}]: {e}", record.account);
+                        false
+                    }
+                })
+                .count();
+            reply.send(saved);
+        }
+        StoreCommand::Load { account, reply } => reply.send(store.load(&account)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use shared::core::request;
+
+    use super::*;
+    use crate::persistence::memory::InMemoryPlayerStore;
+
+    fn record(x: f32) -> PlayerRecord {
+        PlayerRecord {
+            account: "graal".to_string(),
+            display_name: "Graal".to_string(),
+            current_map: "offlinetutorial".to_string(),
+            x,
+            y: 0.0,
+            inventory: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn loads_see_earlier_saves() {
+        let tx = spawn(Arc::new(InMemoryPlayerStore::default()));
+        for x in 1..=50 {
+            tx.send(StoreCommand::Save { record: record(x as f32) }).await.unwrap();
+        }
+        let loaded = request(&tx, |reply| StoreCommand::Load { account: "graal".to_string(), reply }, Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, Some(record(50.0)));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashMap, time::Instant};
+
+use shared::client_messages::ClientOpcode;
+
+/// Size and refill rate of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketConfig {
+    /// Messages that may arrive back to back.
+    pub burst: f32,
+    /// Sustained messages per second.
+    pub per_second: f32,
+}
+
+impl BucketConfig {
+    pub const fn new(burst: f32, per_second: f32) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+struct TokenBucket {
+    config: BucketConfig,
+    tokens: f32,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: BucketConfig, now: Instant) -> Self {
+        Self { config, tokens: config.burst, refilled_at: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.config.per_second).min(self.config.burst);
+        self.refilled_at = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+/// Limits applied to the messages of every connection.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Shared by every message, whatever its opcode.
+    pub global: BucketConfig,
+    /// Tighter limits for individual opcodes, checked on top of `global`.
+    pub per_opcode: HashMap<ClientOpcode, BucketConfig>,
+    /// A connection is kicked once it builds up this many violations. They are forgiven at
+    /// `forgiven_per_second`, so only sustained abuse adds up.
+    pub max_violations: f32,
+    pub forgiven_per_second: f32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            global: BucketConfig::new(60.0, 40.0),
+            per_opcode: HashMap::from([
+                (ClientOpcode::Login, BucketConfig::new(3.0, 0.5)),
+                (ClientOpcode::Register, BucketConfig::new(3.0, 0.5)),
+                (ClientOpcode::MoveInput, BucketConfig::new(40.0, 30.0)),
+                (ClientOpcode::SnapshotAck, BucketConfig::new(40.0, 25.0)),
+                (ClientOpcode::Ping, BucketConfig::new(5.0, 1.0)),
+            ]),
+            max_violations: 20.0,
+            forgiven_per_second: 1.0,
+        }
+    }
+}
+
+/// Something a connection did that it should not have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Sent `opcode` faster than allowed. The message was dropped.
+    RateLimited(ClientOpcode),
+    /// Sent a frame whose payload does not decode as its opcode's message.
+    Undecodable(ClientOpcode),
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RateLimited(opcode) => write!(f, "rate limited on {opcode:?}"),
+            Self::Undecodable(opcode) => write!(f, "undecodable {opcode:?} frame"),
+        }
+    }
+}
+
+/// Token buckets and violation count of a single connection.
+pub struct RateLimiter {
+    limits: RateLimits,
+    global: TokenBucket,
+    per_opcode: HashMap<ClientOpcode, TokenBucket>,
+    /// Drained by violations, the connection is kicked once it is empty.
+    tolerance: TokenBucket,
+    violations: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, now: Instant) -> Self {
+        let tolerance = BucketConfig::new(limits.max_violations, limits.forgiven_per_second);
+        Self {
+            global: TokenBucket::new(limits.global, now),
+            per_opcode: HashMap::default(),
+            tolerance: TokenBucket::new(tolerance, now),
+            violations: 0,
+            limits,
+        }
+    }
+
+    /// Whether a message with `opcode` may go through, taking a token from each bucket it
+    /// counts against if so.
+    pub fn allow(&mut self, opcode: ClientOpcode, now: Instant) -> bool {
+        let mut bucket = self
+            .limits
+            .per_opcode
+            .get(&opcode)
+            .map(|config| self.per_opcode.entry(opcode).or_insert_with(|| TokenBucket::new(*config, now)));
+        // Check both before taking, so a message refused by one bucket does not drain the other.
+        let allowed = self.global.has_token(now) && bucket.as_mut().is_none_or(|bucket| bucket.has_token(now));
+        if allowed {
+            self.global.take();
+            if let Some(bucket) = bucket {
+                bucket.take();
+            }
+        }
+        allowed
+    }
+
+    /// Counts a violation. Returns whether the connection used up its tolerance and should be
+    /// kicked.
+    pub fn record(&mut self, now: Instant) -> bool {
+        self.violations += 1;
+        if self.tolerance.has_token(now) {
+            self.tolerance.take();
+        }
+        !self.tolerance.has_token(now)
+    }
+
+    /// Violations since the connection was opened.
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            global: BucketConfig::new(10.0, 10.0),
+            per_opcode: HashMap::from([(ClientOpcode::Login, BucketConfig::new(2.0, 1.0))]),
+            max_violations: 3.0,
+            forgiven_per_second: 1.0,
+        }
+    }
+
+    #[test]
+    fn refills_buckets_over_time() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(limits(), now);
+        assert!(limiter.allow(ClientOpcode::Login, now));
+        assert!(limiter.allow(ClientOpcode::Login, now));
+        assert!(!limiter.allow(ClientOpcode::Login, now));
+        // Other opcodes only count against the global bucket.
+        assert!(limiter.allow(ClientOpcode::MoveInput, now));
+        assert!(limiter.allow(ClientOpcode::Login, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn kicks_only_sustained_violations() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(limits(), now);
+        assert!(!limiter.record(now));
+        assert!(!limiter.record(now));
+        // One violation was forgiven in the meantime.
+        let later = now + Duration::from_secs(1);
+        assert!(!limiter.record(later));
+        assert!(limiter.record(later));
+        assert_eq!(limiter.violations(), 4);
+    }
+}
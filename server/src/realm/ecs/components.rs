@@ -1,29 +1,105 @@
 use bevy_ecs::prelude::*;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::networking_core::ConnectionId;
+
 // TODO: add npc, projectile, spell? etc? Item?
 #[derive(Component)]
 pub struct Player;
 
-// NOTE: Use this to map to NetEntityIdentifier. A Player will have
-// NetEntityIdentifier::Player(id)
+/// Network identity of a replicated entity. Replication maps players to
+/// `NetEntityIdentifier::Player(id)` and everything else to `NetEntityIdentifier::Npc(id)`.
 #[derive(Component)]
 pub struct Identifier {
     pub id: Uuid,
 }
 
+/// Connection controlling a player entity.
+#[derive(Component, Clone, Copy)]
+pub struct Connection(pub ConnectionId);
+
+/// Movement speed in pixels per second.
+#[derive(Component)]
+pub struct Speed(pub f32);
+
+/// Direction a player is holding, from its latest accepted `MoveInput`.
+#[derive(Component, Default)]
+pub struct MoveState {
+    pub dx: f32,
+    pub dy: f32,
+    pub last_sequence: Option<u32>,
+    pub last_timestamp_ms: u64,
+    /// Realm time the last input was accepted at.
+    pub accepted_at_ms: f64,
+    /// Sequence the client was last sent a `MoveAck` for.
+    pub acked_sequence: Option<u32>,
+}
+
+/// Account the player entity belongs to.
+#[derive(Component)]
+pub struct Account(pub String);
+
+#[derive(Component)]
+pub struct DisplayName(pub String);
+
 #[derive(Component)]
+#[require(PreviousPosition)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
 }
 
+/// Position at the start of the current tick, so moves can be undone.
+#[derive(Component, Default)]
+pub struct PreviousPosition {
+    pub x: f32,
+    pub y: f32,
+}
+
 #[derive(Component, Clone, PartialEq, Eq, Hash)]
 pub struct CurrentMap(pub String);
 
+/// NPC created from the named prototype.
+#[derive(Component)]
+pub struct Npc {
+    pub prototype: &'static str,
+}
+
+/// Static map decoration animated with a gani on the client.
+#[derive(Component)]
+pub struct Gani(pub String);
+
+/// Animation the client plays on the entity, such as `"idle"` or `"walk"`.
+#[derive(Component, PartialEq)]
+pub struct Animation(pub &'static str);
+
+/// Keeps up to `max_population` NPCs of `prototype` alive, replacing each one `respawn_ms`
+/// after it is gone.
+#[derive(Component)]
+pub struct Spawner {
+    pub prototype: &'static str,
+    pub max_population: u32,
+    pub respawn_ms: f32,
+    pub cooldown_ms: f32,
+}
+
+/// Spawner an NPC belongs to, counted against its population cap.
+#[derive(Component)]
+pub struct SpawnedBy(pub Entity);
+
 #[derive(Component)]
 pub struct Collider {
     pub dynamic: bool,
     pub w: f32,
     pub h: f32,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ItemStack {
+    pub item: String,
+    pub count: u32,
+}
+
+#[derive(Component, Default)]
+pub struct Inventory(pub Vec<ItemStack>);
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::*;
+use shared::collision::Grid;
+
+/// A `Grid` per map, so entities on different maps never show up in each other's queries.
+/// Entities are re-bucketed on `flush`, after being marked dirty when they moved or changed maps.
+pub struct MapGrids {
+    cell_size: f32,
+    grids: HashMap<String, Grid<Entity>>,
+    maps: HashMap<Entity, String>,
+}
+
+impl MapGrids {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            grids: HashMap::default(),
+            maps: HashMap::default(),
+        }
+    }
+
+    pub fn mark_dirty(&mut self, entity: Entity, map: &str, x: f32, y: f32, w: f32, h: f32) {
+        if self.maps.get(&entity).is_none_or(|current| current != map) {
+            self.remove(entity);
+            self.maps.insert(entity, map.to_string());
+        }
+        self.grids
+            .entry(map.to_string())
+            .or_insert_with(|| Grid::new(self.cell_size))
+            .mark_dirty(entity, x, y, w, h);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        if let Some(map) = self.maps.remove(&entity)
+            && let Some(grid) = self.grids.get_mut(&map)
+        {
+            grid.remove(&entity);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for grid in self.grids.values_mut() {
+            grid.flush();
+        }
+    }
+
+    pub fn get(&self, map: &str) -> Option<&Grid<Entity>> {
+        self.grids.get(map)
+    }
+}
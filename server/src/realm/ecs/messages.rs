@@ -0,0 +1,29 @@
+use bevy_ecs::prelude::*;
+
+/// Two colliders started overlapping. `a` is always the lower entity.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionStarted {
+    pub a: Entity,
+    pub b: Entity,
+}
+
+/// Two colliders stopped overlapping, or one of them is gone. `a` is always the lower entity.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionEnded {
+    pub a: Entity,
+    pub b: Entity,
+}
+
+/// `entity` came within view of the player entity `viewer`.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnteredView {
+    pub viewer: Entity,
+    pub entity: Entity,
+}
+
+/// `entity` went out of view of `viewer`, changed maps or is gone.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeftView {
+    pub viewer: Entity,
+    pub entity: Entity,
+}
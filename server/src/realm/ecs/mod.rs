@@ -1,4 +1,6 @@
 pub mod systems;
 
 pub mod components;
+pub mod map_grids;
+pub mod messages;
 pub mod resources;
@@ -1,10 +1,35 @@
+use std::collections::HashSet;
+
 use bevy_ecs::prelude::*;
-use tokio::sync::mpsc;
+use shared::channel::Sender;
 
 use crate::realm::realm_core::RealmEvent;
 
 #[derive(Resource)]
-pub struct RealmEventSender(pub mpsc::UnboundedSender<RealmEvent>);
+pub struct RealmEventSender(pub Sender<RealmEvent>);
 
-#[derive(Resource)]
-pub struct ElapsedTimeMs(pub f32);
+/// Simulation clock, moved forward by one fixed step at the start of every tick.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct Time {
+    /// Index of the running tick, the first one is 1.
+    pub tick: u64,
+    /// Simulated milliseconds per tick.
+    pub delta_ms: f32,
+    /// Simulated milliseconds since the realm started.
+    pub elapsed_ms: f64,
+}
+
+impl Time {
+    pub fn new(delta_ms: f32) -> Self {
+        Self { tick: 0, delta_ms, elapsed_ms: 0.0 }
+    }
+
+    pub fn advance(&mut self) {
+        self.tick += 1;
+        self.elapsed_ms += f64::from(self.delta_ms);
+    }
+}
+
+/// Collider pairs that overlapped on the last collision pass, lower entity first.
+#[derive(Resource, Default)]
+pub struct ActiveCollisions(pub HashSet<(Entity, Entity)>);
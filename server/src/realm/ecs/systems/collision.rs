@@ -1,28 +1,238 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use bevy_ecs::prelude::*;
-use parry2d::bounding_volume::Aabb;
-use shared::collision::Grid;
+use parry2d::{bounding_volume::Aabb, na::Vector2};
 
-use crate::realm::ecs::components::{Collider, CurrentMap, Position};
+use crate::realm::ecs::{
+    components::{Collider, CurrentMap, Position},
+    map_grids::MapGrids,
+    messages::{CollisionEnded, CollisionStarted},
+    resources::ActiveCollisions,
+};
 
+/// Two tiles; most colliders fit in a single cell.
+const CELL_SIZE: f32 = 32.0;
+
+/// Broad phase index of every collider. Only colliders that moved, resized, changed maps or were
+/// pushed are re-bucketed each tick.
+#[derive(Resource)]
+pub struct CollisionGrids(MapGrids);
+
+impl Default for CollisionGrids {
+    fn default() -> Self {
+        Self(MapGrids::new(CELL_SIZE))
+    }
+}
+
+struct Body {
+    aabb: Aabb,
+    dynamic: bool,
+}
+
+/// Pushes dynamic colliders out of static ones and out of each other, and reports which pairs
+/// started or stopped overlapping.
+///
+/// Each overlap is resolved along its shallowest axis. Dynamic pairs split the push, static
+/// colliders never move. Pushes from several overlaps are summed and applied once, so deep
+/// pileups may take a few ticks to settle.
 pub fn resolve_collisions(
-    query: Query<(Entity, &mut Position, &Collider, &CurrentMap)>,
+    mut query: Query<(Entity, &mut Position, Ref<Collider>, Ref<CurrentMap>)>,
+    mut grids: ResMut<CollisionGrids>,
+    mut removed: RemovedComponents<Collider>,
+    mut active: ResMut<ActiveCollisions>,
+    mut started: MessageWriter<CollisionStarted>,
+    mut ended: MessageWriter<CollisionEnded>,
 ) {
-    // TODO: filter by current map, only check entities against each other if they are in the same
-    // map
+    let grids = &mut grids.0;
+    for entity in removed.read() {
+        grids.remove(entity);
+    }
+    for (entity, position, collider, map) in query.iter_mut() {
+        if position.is_changed() || collider.is_changed() || map.is_changed() {
+            grids.mark_dirty(entity, &map.0, position.x, position.y, collider.w, collider.h);
+        }
+    }
+    grids.flush();
 
-    let mut per_map: HashMap<&CurrentMap, Vec<(Entity, &Position, &Collider)>> = HashMap::default();
+    let mut per_map: HashMap<&CurrentMap, HashMap<Entity, Body>> = HashMap::default();
     for (entity, position, collider, map) in query.iter() {
-        per_map.entry(map).or_default().push((entity, position, collider));
-    }
-    for (_, entities) in per_map {
-        let mut grid: Grid<Entity> = Grid::new(10.0);
-        let mut aabbs = HashMap::default();
-        for (entity, position, collider) in entities {
-            let mins = parry2d::na::point!(position.x - collider.w / 2.0, position.y - collider.h / 2.0);
-            let maxs = parry2d::na::point!(position.x + collider.w / 2.0, position.y + collider.h / 2.0);
-            aabbs.insert(entity, Aabb::new(mins, maxs));
+        let mins = parry2d::na::point!(position.x - collider.w / 2.0, position.y - collider.h / 2.0);
+        let maxs = parry2d::na::point!(position.x + collider.w / 2.0, position.y + collider.h / 2.0);
+        per_map.entry(map.into_inner()).or_default().insert(
+            entity,
+            Body {
+                aabb: Aabb::new(mins, maxs),
+                dynamic: collider.dynamic,
+            },
+        );
+    }
+
+    let mut pairs: HashSet<(Entity, Entity)> = HashSet::default();
+    let mut pushes: HashMap<Entity, Vector2<f32>> = HashMap::default();
+    let mut candidates: Vec<Entity> = Vec::new();
+    for (map, bodies) in &per_map {
+        let Some(grid) = grids.get(&map.0) else {
+            continue;
+        };
+        for (entity, body) in bodies.iter().filter(|(_, body)| body.dynamic) {
+            let (center, extents) = (body.aabb.center(), body.aabb.extents());
+            grid.query_into(center.x, center.y, extents.x, extents.y, &mut candidates);
+            for &other in &candidates {
+                let Some(other_body) = bodies.get(&other) else {
+                    continue;
+                };
+                // Dynamic pairs are seen from both sides, resolve them once.
+                if other == *entity || (other_body.dynamic && other < *entity) {
+                    continue;
+                }
+                let Some(push) = penetration(&body.aabb, &other_body.aabb) else {
+                    continue;
+                };
+                pairs.insert(((*entity).min(other), (*entity).max(other)));
+                if other_body.dynamic {
+                    *pushes.entry(*entity).or_insert_with(Vector2::zeros) += push / 2.0;
+                    *pushes.entry(other).or_insert_with(Vector2::zeros) -= push / 2.0;
+                } else {
+                    *pushes.entry(*entity).or_insert_with(Vector2::zeros) += push;
+                }
+            }
+        }
+    }
+    drop(per_map);
+
+    // The next run cannot see changes made by this one, re-bucket pushed colliders now.
+    for (entity, push) in pushes {
+        if let Ok((_, mut position, collider, map)) = query.get_mut(entity) {
+            position.x += push.x;
+            position.y += push.y;
+            grids.mark_dirty(entity, &map.0, position.x, position.y, collider.w, collider.h);
+        }
+    }
+    grids.flush();
+    for &(a, b) in pairs.difference(&active.0) {
+        started.write(CollisionStarted { a, b });
+    }
+    for &(a, b) in active.0.difference(&pairs) {
+        ended.write(CollisionEnded { a, b });
+    }
+    active.0 = pairs;
+}
+
+/// Smallest translation moving `a` out of `b`, if they overlap. Touching edges do not count.
+fn penetration(a: &Aabb, b: &Aabb) -> Option<Vector2<f32>> {
+    let overlap_x = a.maxs.x.min(b.maxs.x) - a.mins.x.max(b.mins.x);
+    let overlap_y = a.maxs.y.min(b.maxs.y) - a.mins.y.max(b.mins.y);
+    if overlap_x <= 0.0 || overlap_y <= 0.0 {
+        return None;
+    }
+    let (a_center, b_center) = (a.center(), b.center());
+    if overlap_x < overlap_y {
+        let direction = if a_center.x < b_center.x { -1.0 } else { 1.0 };
+        Some(Vector2::new(direction * overlap_x, 0.0))
+    } else {
+        let direction = if a_center.y < b_center.y { -1.0 } else { 1.0 };
+        Some(Vector2::new(0.0, direction * overlap_y))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy_ecs::message::MessageRegistry;
+
+    use super::*;
+
+    fn setup() -> (World, Schedule) {
+        let mut world = World::new();
+        world.init_resource::<ActiveCollisions>();
+        world.init_resource::<CollisionGrids>();
+        MessageRegistry::register_message::<CollisionStarted>(&mut world);
+        MessageRegistry::register_message::<CollisionEnded>(&mut world);
+        let mut schedule = Schedule::default();
+        schedule.add_systems(resolve_collisions);
+        (world, schedule)
+    }
+
+    fn spawn(world: &mut World, x: f32, y: f32, dynamic: bool) -> Entity {
+        world
+            .spawn((
+                Position { x, y },
+                Collider { dynamic, w: 16.0, h: 16.0 },
+                CurrentMap("map".to_string()),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn pushes_dynamic_out_of_static() {
+        let (mut world, mut schedule) = setup();
+        let wall = spawn(&mut world, 0.0, 0.0, false);
+        let player = spawn(&mut world, 12.0, 2.0, true);
+        schedule.run(&mut world);
+        let position = world.get::<Position>(player).unwrap();
+        assert_eq!((position.x, position.y), (16.0, 2.0));
+        let wall_position = world.get::<Position>(wall).unwrap();
+        assert_eq!((wall_position.x, wall_position.y), (0.0, 0.0));
+        assert_eq!(world.resource::<Messages<CollisionStarted>>().len(), 1);
+    }
+
+    #[test]
+    fn separates_dynamic_pairs_and_reports_end() {
+        let (mut world, mut schedule) = setup();
+        let a = spawn(&mut world, 0.0, 0.0, true);
+        let b = spawn(&mut world, 0.0, 12.0, true);
+        // Same position on another map never collides.
+        let c = spawn(&mut world, 0.0, 0.0, true);
+        world.entity_mut(c).insert(CurrentMap("other".to_string()));
+
+        schedule.run(&mut world);
+        let (a_y, b_y) = (world.get::<Position>(a).unwrap().y, world.get::<Position>(b).unwrap().y);
+        assert_eq!((a_y, b_y), (-2.0, 14.0));
+        assert_eq!(world.resource::<ActiveCollisions>().0.len(), 1);
+
+        schedule.run(&mut world);
+        assert!(world.resource::<ActiveCollisions>().0.is_empty());
+        assert_eq!(world.resource::<Messages<CollisionEnded>>().len(), 1);
+    }
+
+    #[test]
+    fn tracks_moved_and_despawned_colliders() {
+        let (mut world, mut schedule) = setup();
+        let wall = spawn(&mut world, 0.0, 0.0, false);
+        let player = spawn(&mut world, 100.0, 0.0, true);
+        schedule.run(&mut world);
+        assert!(world.resource::<ActiveCollisions>().0.is_empty());
+
+        world.get_mut::<Position>(player).unwrap().x = 12.0;
+        schedule.run(&mut world);
+        assert_eq!(world.get::<Position>(player).unwrap().x, 16.0);
+
+        world.despawn(wall);
+        world.get_mut::<Position>(player).unwrap().x = 0.0;
+        schedule.run(&mut world);
+        assert_eq!(world.get::<Position>(player).unwrap().x, 0.0);
+        assert!(world.resource::<ActiveCollisions>().0.is_empty());
+    }
+
+    #[test]
+    fn keeps_pushed_colliders_in_their_new_cells() {
+        let (mut world, mut schedule) = setup();
+        // Spawned first, so it resolves the pair: it must find the pushed collider in the grid.
+        let pusher = spawn(&mut world, 12.0, 0.0, true);
+        let npc = spawn(&mut world, 24.0, 0.0, true);
+        for _ in 0..40 {
+            let npc_x = world.get::<Position>(npc).unwrap().x;
+            world.get_mut::<Position>(pusher).unwrap().x = npc_x - 12.0;
+            schedule.run(&mut world);
         }
+        // Pushed two pixels a tick, several cells away from where it started.
+        assert_eq!(world.get::<Position>(npc).unwrap().x, 104.0);
+        let grid = world.resource::<CollisionGrids>().0.get("map").unwrap();
+        assert!(grid.query(104.0, 0.0, 1.0, 1.0).contains(&npc));
+
+        // Still found from its new cell.
+        world.get_mut::<Position>(pusher).unwrap().x = 116.0;
+        schedule.run(&mut world);
+        assert_eq!(world.get::<Position>(npc).unwrap().x, 102.0);
+        assert_eq!(world.resource::<ActiveCollisions>().0.len(), 1);
     }
 }
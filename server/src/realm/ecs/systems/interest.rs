@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy_ecs::prelude::*;
+
+use crate::realm::ecs::{
+    components::{Connection, CurrentMap, Identifier, Position},
+    map_grids::MapGrids,
+    messages::{EnteredView, LeftView},
+};
+
+/// Entities closer than this to a player come into its view.
+pub const VIEW_RADIUS: f32 = 320.0;
+/// Entities already in view stay until they are this far, so walking along the edge of the view
+/// does not spawn and despawn them over and over.
+pub const LEAVE_RADIUS: f32 = VIEW_RADIUS + 32.0;
+const CELL_SIZE: f32 = 64.0;
+
+/// What every connected player can see: identified entities on its map, within its view radius.
+#[derive(Resource)]
+pub struct Interest {
+    grids: MapGrids,
+    views: HashMap<Entity, HashSet<Entity>>,
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Self {
+            grids: MapGrids::new(CELL_SIZE),
+            views: HashMap::default(),
+        }
+    }
+}
+
+impl Interest {
+    /// Entities in view of `viewer`, including itself.
+    pub fn visible(&self, viewer: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.views.get(&viewer).into_iter().flatten().copied()
+    }
+
+    /// Players that can see `entity`.
+    pub fn viewers(&self, entity: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.views
+            .iter()
+            .filter(move |(_, visible)| visible.contains(&entity))
+            .map(|(viewer, _)| *viewer)
+    }
+}
+
+/// Recomputes every player's view and reports entities entering and leaving it.
+pub fn update_interest(
+    mut interest: ResMut<Interest>,
+    entities: Query<(Entity, Ref<Position>, Ref<CurrentMap>), With<Identifier>>,
+    viewers: Query<(Entity, &Position, &CurrentMap), With<Connection>>,
+    mut removed: RemovedComponents<Identifier>,
+    mut entered: MessageWriter<EnteredView>,
+    mut left: MessageWriter<LeftView>,
+) {
+    let interest = interest.as_mut();
+    for entity in removed.read() {
+        interest.grids.remove(entity);
+    }
+    for (entity, position, map) in entities.iter() {
+        if position.is_changed() || map.is_changed() {
+            interest.grids.mark_dirty(entity, &map.0, position.x, position.y, 0.0, 0.0);
+        }
+    }
+    interest.grids.flush();
+
+    interest.views.retain(|viewer, _| viewers.contains(*viewer));
+    let mut nearby = Vec::new();
+    for (viewer, position, map) in viewers.iter() {
+        nearby.clear();
+        if let Some(grid) = interest.grids.get(&map.0) {
+            grid.query_radius_into(position.x, position.y, LEAVE_RADIUS, &mut nearby);
+        }
+        let view = interest.views.entry(viewer).or_default();
+        let visible: HashSet<Entity> = nearby
+            .iter()
+            .copied()
+            .filter(|entity| {
+                view.contains(entity)
+                    || entities.get(*entity).is_ok_and(|(_, other, _)| {
+                        (other.x - position.x).hypot(other.y - position.y) <= VIEW_RADIUS
+                    })
+            })
+            .collect();
+        for &entity in visible.difference(view) {
+            entered.write(EnteredView { viewer, entity });
+        }
+        for &entity in view.difference(&visible) {
+            left.write(LeftView { viewer, entity });
+        }
+        *view = visible;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU64;
+
+    use bevy_ecs::message::{MessageRegistry, message_update_system};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::networking_core::ConnectionId;
+
+    fn spawn(world: &mut World, x: f32, map: &str) -> Entity {
+        world
+            .spawn((Identifier { id: Uuid::new_v4() }, Position { x, y: 0.0 }, CurrentMap(map.to_string())))
+            .id()
+    }
+
+    fn entered(world: &World) -> HashSet<Entity> {
+        let messages = world.resource::<Messages<EnteredView>>();
+        messages.iter_current_update_messages().map(|message| message.entity).collect()
+    }
+
+    fn left(world: &World) -> HashSet<Entity> {
+        let messages = world.resource::<Messages<LeftView>>();
+        messages.iter_current_update_messages().map(|message| message.entity).collect()
+    }
+
+    #[test]
+    fn enters_and_leaves_view_with_hysteresis() {
+        let mut world = World::new();
+        world.init_resource::<Interest>();
+        MessageRegistry::register_message::<EnteredView>(&mut world);
+        MessageRegistry::register_message::<LeftView>(&mut world);
+        let mut schedule = Schedule::default();
+        schedule.add_systems((message_update_system, update_interest).chain());
+
+        let player = spawn(&mut world, 0.0, "a");
+        world.entity_mut(player).insert(Connection(ConnectionId::next(&AtomicU64::new(0))));
+        let near = spawn(&mut world, VIEW_RADIUS - 1.0, "a");
+        let far = spawn(&mut world, VIEW_RADIUS + 1.0, "a");
+        spawn(&mut world, 0.0, "b");
+
+        schedule.run(&mut world);
+        assert_eq!(entered(&world), HashSet::from([player, near]));
+
+        // Between the two radii, `near` stays and `far` still does not enter.
+        world.get_mut::<Position>(near).unwrap().x = VIEW_RADIUS + 1.0;
+        schedule.run(&mut world);
+        assert!(entered(&world).is_empty() && left(&world).is_empty());
+        assert!(!world.resource::<Interest>().visible(player).any(|entity| entity == far));
+
+        world.get_mut::<Position>(near).unwrap().x = LEAVE_RADIUS + 1.0;
+        schedule.run(&mut world);
+        assert_eq!(left(&world), HashSet::from([near]));
+
+        world.despawn(player);
+        schedule.run(&mut world);
+        assert!(world.resource::<Interest>().visible(player).next().is_none());
+    }
+}
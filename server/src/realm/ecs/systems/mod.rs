@@ -1 +1,6 @@
 pub mod collision;
+pub mod interest;
+pub mod movement;
+pub mod replication;
+pub mod spawner;
+pub mod terrain;
@@ -0,0 +1,158 @@
+use bevy_ecs::prelude::*;
+use shared::{client_messages::MoveInput, server_messages::{FromServer, MoveAck}};
+
+use crate::realm::{
+    ecs::{
+        components::{Animation, Connection, MoveState, Position, Speed},
+        resources::{RealmEventSender, Time},
+    },
+    realm_core::RealmEvent,
+};
+
+/// Longest step a single tick may move anything. Ticks are fixed steps, so this only bites at
+/// very low tick rates, where it keeps moves short enough for collisions to catch.
+const MAX_STEP_MS: f32 = 250.0;
+/// How far a client's clock may run ahead of the realm's between two inputs. Covers jitter in
+/// when inputs arrive, not a clock running fast for long.
+const MAX_CLOCK_DRIFT_MS: u64 = 2000;
+pub const IDLE_ANIMATION: &str = "idle";
+pub const WALK_ANIMATION: &str = "walk";
+
+/// Why a `MoveInput` was dropped.
+#[derive(Debug, PartialEq)]
+pub enum MoveRejection {
+    /// Sequence is not newer than the last accepted one, a duplicate or a reordered packet.
+    Stale,
+    /// Timestamp went backwards.
+    TimeTravel,
+    /// Timestamp is further ahead of the last accepted one than the realm's clock moved since.
+    FromTheFuture,
+    /// Direction is NaN or infinite.
+    InvalidDirection,
+}
+
+impl std::fmt::Display for MoveRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stale => write!(f, "stale sequence"),
+            Self::TimeTravel => write!(f, "timestamp went backwards"),
+            Self::FromTheFuture => write!(f, "timestamp too far ahead"),
+            Self::InvalidDirection => write!(f, "invalid direction"),
+        }
+    }
+}
+
+impl MoveState {
+    /// Records `input`, received at realm time `now_ms`, as the direction to move in from now on.
+    /// Directions longer than one are scaled down, so a client can never ask for more than its
+    /// speed.
+    pub fn accept(&mut self, input: &MoveInput, now_ms: f64) -> Result<(), MoveRejection> {
+        if self.last_sequence.is_some_and(|last| input.sequence <= last) {
+            return Err(MoveRejection::Stale);
+        }
+        if input.timestamp_ms < self.last_timestamp_ms {
+            return Err(MoveRejection::TimeTravel);
+        }
+        // Otherwise a timestamp of u64::MAX would leave every later input travelling back in time.
+        let since_accepted_ms = (now_ms - self.accepted_at_ms).max(0.0) as u64;
+        if self.last_sequence.is_some()
+            && input.timestamp_ms - self.last_timestamp_ms > since_accepted_ms.saturating_add(MAX_CLOCK_DRIFT_MS)
+        {
+            return Err(MoveRejection::FromTheFuture);
+        }
+        if !input.dx.is_finite() || !input.dy.is_finite() {
+            return Err(MoveRejection::InvalidDirection);
+        }
+        let length = input.dx.hypot(input.dy);
+        let scale = if length > 1.0 { 1.0 / length } else { 1.0 };
+        self.dx = input.dx * scale;
+        self.dy = input.dy * scale;
+        self.last_sequence = Some(input.sequence);
+        self.last_timestamp_ms = input.timestamp_ms;
+        self.accepted_at_ms = now_ms;
+        Ok(())
+    }
+}
+
+/// Moves every entity along its held direction at its speed. Runs before collisions and terrain
+/// so both can undo impossible moves in the same tick.
+pub fn apply_movement(
+    time: Res<Time>,
+    mut query: Query<(&mut Position, &MoveState, &Speed, Option<&mut Animation>)>,
+) {
+    let seconds = time.delta_ms.clamp(0.0, MAX_STEP_MS) / 1000.0;
+    for (mut position, state, speed, animation) in query.iter_mut() {
+        let moving = state.dx != 0.0 || state.dy != 0.0;
+        if let Some(mut animation) = animation {
+            animation.set_if_neq(Animation(if moving { WALK_ANIMATION } else { IDLE_ANIMATION }));
+        }
+        if !moving {
+            continue;
+        }
+        position.x += state.dx * speed.0 * seconds;
+        position.y += state.dy * speed.0 * seconds;
+    }
+}
+
+/// Tells clients which of their inputs has been applied and where it left them. Runs last, once
+/// the position has been corrected.
+pub fn send_move_acks(
+    events: Res<RealmEventSender>,
+    mut query: Query<(&Connection, &Position, &mut MoveState)>,
+) {
+    for (connection, position, mut state) in query.iter_mut() {
+        let Some(sequence) = state.last_sequence else {
+            continue;
+        };
+        if state.acked_sequence == Some(sequence) {
+            continue;
+        }
+        state.acked_sequence = Some(sequence);
+        let message = FromServer::MoveAck(MoveAck { sequence, x: position.x, y: position.y });
+        if let Err(e) = events.0.try_send(RealmEvent::Send { connection_id: connection.0, message }) {
+            warn!("Failed to send RealmEvent: [{e}]");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input(sequence: u32, dx: f32, dy: f32, timestamp_ms: u64) -> MoveInput {
+        MoveInput { sequence, dx, dy, timestamp_ms }
+    }
+
+    #[test]
+    fn rejects_stale_and_invalid_input() {
+        let mut state = MoveState::default();
+        assert_eq!(state.accept(&input(2, 1.0, 0.0, 100), 0.0), Ok(()));
+        assert_eq!(state.accept(&input(2, 0.0, 1.0, 120), 0.0), Err(MoveRejection::Stale));
+        assert_eq!(state.accept(&input(1, 0.0, 1.0, 120), 0.0), Err(MoveRejection::Stale));
+        assert_eq!(state.accept(&input(3, 0.0, 1.0, 90), 0.0), Err(MoveRejection::TimeTravel));
+        assert_eq!(state.accept(&input(3, f32::NAN, 1.0, 120), 0.0), Err(MoveRejection::InvalidDirection));
+        assert_eq!(state.accept(&input(3, 0.0, 1.0, u64::MAX), 0.0), Err(MoveRejection::FromTheFuture));
+        assert_eq!((state.dx, state.dy, state.last_sequence), (1.0, 0.0, Some(2)));
+        // A client idle for a minute is not ahead of the realm.
+        assert_eq!(state.accept(&input(3, 0.0, 1.0, 60_100), 60_000.0), Ok(()));
+        assert_eq!(state.accept(&input(4, 0.0, 1.0, 60_100 + MAX_CLOCK_DRIFT_MS + 51), 60_050.0), Err(MoveRejection::FromTheFuture));
+    }
+
+    #[test]
+    fn caps_speed() {
+        let mut world = World::new();
+        world.insert_resource(Time::new(1000.0));
+        let mut state = MoveState::default();
+        // A speed hack asking for a hundred times the normal speed.
+        state.accept(&input(0, 300.0, 400.0, 0), 0.0).unwrap();
+        let entity = world.spawn((Position { x: 0.0, y: 0.0 }, state, Speed(100.0))).id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(apply_movement);
+        schedule.run(&mut world);
+
+        // Clamped to one unit direction and to the longest step.
+        let position = world.get::<Position>(entity).unwrap();
+        assert!((position.x - 15.0).abs() < 1e-4 && (position.y - 20.0).abs() < 1e-4);
+    }
+}
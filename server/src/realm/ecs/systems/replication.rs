@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bevy_ecs::prelude::*;
+use shared::{
+    identifier::NetEntityIdentifier,
+    replication::EntityState,
+    server_messages::{EntityDespawned, EntitySpawned, FromServer, Snapshot},
+};
+
+use crate::{
+    networking_core::ConnectionId,
+    realm::{
+        ecs::{
+            components::{Animation, Connection, Gani, Identifier, Npc, Player, Position},
+            messages::{EnteredView, LeftView},
+            resources::{RealmEventSender, Time},
+        },
+        realm_core::RealmEvent,
+    },
+};
+
+/// Unacknowledged snapshots kept per client. A client that acknowledges none of this many
+/// snapshots is sent full state again.
+const HISTORY_LENGTH: usize = 32;
+
+type Entities = HashMap<NetEntityIdentifier, EntityState>;
+
+/// Components an entity's `EntityState` is built from.
+type Replicated = (
+    Entity,
+    &'static Identifier,
+    &'static Position,
+    Has<Player>,
+    Option<&'static Npc>,
+    Option<&'static Gani>,
+    Option<&'static Animation>,
+);
+
+/// What one client has been told.
+#[derive(Default)]
+struct ClientReplication {
+    /// Entities in the client's view, which it was sent a spawn for and no despawn since.
+    known: HashMap<Entity, NetEntityIdentifier>,
+    /// Snapshots sent and not yet superseded by an acknowledgement, oldest first.
+    history: VecDeque<(u32, Entities)>,
+    /// Last acknowledged snapshot, the baseline of the next one.
+    baseline: Option<(u32, Entities)>,
+}
+
+/// Replication state of every connected player.
+#[derive(Resource, Default)]
+pub struct Replication {
+    clients: HashMap<ConnectionId, ClientReplication>,
+}
+
+impl Replication {
+    /// Makes snapshot `tick` the baseline of `connection_id`'s later snapshots. Acknowledgements
+    /// older than the current baseline or for snapshots no longer kept are ignored.
+    pub fn acknowledge(&mut self, connection_id: ConnectionId, tick: u32) {
+        let Some(client) = self.clients.get_mut(&connection_id) else {
+            return;
+        };
+        let Some(index) = client.history.iter().position(|(sent, _)| *sent == tick) else {
+            return;
+        };
+        client.history.drain(..index);
+        client.baseline = client.history.pop_front();
+    }
+}
+
+/// Current state of every replicated entity, computed once per tick.
+fn entity_states(entities: &Query<Replicated>) -> HashMap<Entity, (NetEntityIdentifier, EntityState)> {
+    entities
+        .iter()
+        .map(|(entity, identifier, position, is_player, npc, gani, animation)| {
+            let id = if is_player {
+                NetEntityIdentifier::Player(identifier.id)
+            } else {
+                NetEntityIdentifier::Npc(identifier.id)
+            };
+            let appearance = gani
+                .map(|gani| gani.0.clone())
+                .or_else(|| npc.map(|npc| npc.prototype.to_string()));
+            let state = EntityState {
+                x: position.x,
+                y: position.y,
+                appearance,
+                animation: animation.map(|animation| animation.0.to_string()),
+            };
+            (entity, (id, state))
+        })
+        .collect()
+}
+
+/// Sends every player the entities in its view: explicit spawns and despawns as they enter and
+/// leave it, then a snapshot of what changed since the last snapshot the client acknowledged.
+pub fn replicate(
+    time: Res<Time>,
+    events: Res<RealmEventSender>,
+    mut replication: ResMut<Replication>,
+    mut entered: MessageReader<EnteredView>,
+    mut left: MessageReader<LeftView>,
+    clients: Query<&Connection, With<Player>>,
+    entities: Query<Replicated>,
+) {
+    let replication = replication.as_mut();
+    // Truncated on the wire. Acknowledgements are matched by equality, so wrapping is harmless.
+    let tick = time.tick as u32;
+    let send = |event| {
+        if let Err(e) = events.0.try_send(event) {
+            warn!("Failed to send RealmEvent: [{e}]");
+        }
+    };
+
+    let connected: HashSet<ConnectionId> = clients.iter().map(|connection| connection.0).collect();
+    replication.clients.retain(|connection_id, _| connected.contains(connection_id));
+    let states = entity_states(&entities);
+
+    // Spawns and despawns read the same for every viewer, each goes out once to all of them.
+    let mut despawns: HashMap<NetEntityIdentifier, Vec<ConnectionId>> = HashMap::new();
+    for LeftView { viewer, entity } in left.read() {
+        if let Ok(connection) = clients.get(*viewer)
+            && let Some(client) = replication.clients.get_mut(&connection.0)
+            && let Some(id) = client.known.remove(entity)
+        {
+            despawns.entry(id).or_default().push(connection.0);
+        }
+    }
+    for (id, connection_ids) in despawns {
+        send(RealmEvent::Multicast { connection_ids, message: FromServer::EntityDespawned(EntityDespawned { id }) });
+    }
+    let mut spawned = HashSet::new();
+    let mut spawns: HashMap<Entity, Vec<ConnectionId>> = HashMap::new();
+    for EnteredView { viewer, entity } in entered.read() {
+        let (Ok(connection), Some((id, _))) = (clients.get(*viewer), states.get(entity)) else {
+            continue;
+        };
+        let client = replication.clients.entry(connection.0).or_default();
+        client.known.insert(*entity, *id);
+        // Forget what the client knew from an earlier visit, it starts over from the spawn.
+        for (_, entities) in client.baseline.iter_mut().chain(client.history.iter_mut()) {
+            entities.remove(id);
+        }
+        spawned.insert((connection.0, *id));
+        spawns.entry(*entity).or_default().push(connection.0);
+    }
+    for (entity, connection_ids) in spawns {
+        let (id, state) = &states[&entity];
+        let message = FromServer::EntitySpawned(EntitySpawned { id: *id, state: state.clone() });
+        send(RealmEvent::Multicast { connection_ids, message });
+    }
+
+    for (connection_id, client) in replication.clients.iter_mut() {
+        // Every snapshot since the baseline went unacknowledged, the client may not be able to
+        // apply deltas against it any more.
+        if client.history.len() == HISTORY_LENGTH {
+            client.history.pop_front();
+            client.baseline = None;
+        }
+        let visible: Entities = client
+            .known
+            .keys()
+            .filter_map(|entity| states.get(entity))
+            .map(|(id, state)| (*id, state.clone()))
+            .collect();
+        let baseline = client.baseline.as_ref();
+        let deltas: Vec<_> = visible
+            .iter()
+            .filter(|(id, _)| !spawned.contains(&(*connection_id, **id)))
+            .filter_map(|(id, state)| state.diff(*id, baseline.and_then(|(_, entities)| entities.get(id))))
+            .collect();
+        if deltas.is_empty() {
+            continue;
+        }
+        let baseline = baseline.map(|(baseline, _)| *baseline);
+        client.history.push_back((tick, visible));
+        let message = FromServer::Snapshot(Snapshot { tick, baseline, entities: deltas });
+        send(RealmEvent::Send { connection_id: *connection_id, message });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU64;
+
+    use bevy_ecs::message::{MessageRegistry, message_update_system};
+    use shared::channel::{self, Receiver};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::realm::ecs::{components::CurrentMap, systems::interest::{Interest, update_interest}};
+
+    fn setup() -> (World, Schedule, Receiver<RealmEvent>) {
+        let mut world = World::new();
+        let (tx, rx) = channel::unbounded_channel();
+        world.insert_resource(RealmEventSender(tx));
+        world.insert_resource(Time::new(50.0));
+        world.init_resource::<Replication>();
+        world.init_resource::<Interest>();
+        MessageRegistry::register_message::<EnteredView>(&mut world);
+        MessageRegistry::register_message::<LeftView>(&mut world);
+        let mut schedule = Schedule::default();
+        schedule.add_systems((message_update_system, update_interest, replicate).chain());
+        (world, schedule, rx)
+    }
+
+    fn sent(rx: &mut Receiver<RealmEvent>) -> Vec<FromServer> {
+        std::iter::from_fn(|| rx.try_recv())
+            .filter_map(|event| match event {
+                RealmEvent::Send { message, .. } | RealmEvent::Multicast { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn run(world: &mut World, schedule: &mut Schedule) {
+        world.resource_mut::<Time>().advance();
+        schedule.run(world);
+    }
+
+    fn single_snapshot(rx: &mut Receiver<RealmEvent>) -> Snapshot {
+        match sent(rx).as_slice() {
+            [FromServer::Snapshot(snapshot)] => snapshot.clone(),
+            other => panic!("expected a single snapshot, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn spawns_then_sends_deltas_against_the_acknowledged_snapshot() {
+        let (mut world, mut schedule, mut rx) = setup();
+        let connection = ConnectionId::next(&AtomicU64::new(0));
+        world.spawn((
+            Player,
+            Identifier { id: Uuid::new_v4() },
+            Position { x: 0.0, y: 0.0 },
+            CurrentMap("a".to_string()),
+            Connection(connection),
+        ));
+        let npc = world
+            .spawn((Npc { prototype: "bomy" }, Identifier { id: Uuid::new_v4() }, Position { x: 8.0, y: 8.0 }, CurrentMap("a".to_string())))
+            .id();
+        // Other maps are not replicated.
+        world.spawn((Identifier { id: Uuid::new_v4() }, Position { x: 0.0, y: 0.0 }, CurrentMap("b".to_string())));
+
+        run(&mut world, &mut schedule);
+        let messages = sent(&mut rx);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|message| matches!(message, FromServer::EntitySpawned(_))));
+
+        // Nothing acknowledged yet, so everything goes out in full.
+        run(&mut world, &mut schedule);
+        let snapshot = single_snapshot(&mut rx);
+        assert_eq!((snapshot.baseline, snapshot.entities.len()), (None, 2));
+
+        world.resource_mut::<Replication>().acknowledge(connection, snapshot.tick);
+        run(&mut world, &mut schedule);
+        assert!(sent(&mut rx).is_empty());
+
+        world.get_mut::<Position>(npc).unwrap().x = 12.0;
+        run(&mut world, &mut schedule);
+        let delta = single_snapshot(&mut rx);
+        assert_eq!(delta.baseline, Some(snapshot.tick));
+        assert_eq!((delta.entities.len(), delta.entities[0].x, delta.entities[0].y), (1, Some(12.0), None));
+
+        world.despawn(npc);
+        run(&mut world, &mut schedule);
+        assert!(matches!(sent(&mut rx).as_slice(), [FromServer::EntityDespawned(_)]));
+    }
+
+    #[test]
+    fn sends_full_state_to_clients_that_stop_acknowledging() {
+        let (mut world, mut schedule, mut rx) = setup();
+        let connection = ConnectionId::next(&AtomicU64::new(0));
+        world.spawn((
+            Player,
+            Identifier { id: Uuid::new_v4() },
+            Position { x: 0.0, y: 0.0 },
+            CurrentMap("a".to_string()),
+            Connection(connection),
+        ));
+        let npc = world
+            .spawn((Npc { prototype: "bomy" }, Identifier { id: Uuid::new_v4() }, Position { x: 8.0, y: 8.0 }, CurrentMap("a".to_string())))
+            .id();
+        run(&mut world, &mut schedule);
+        sent(&mut rx);
+        run(&mut world, &mut schedule);
+        let acknowledged = single_snapshot(&mut rx).tick;
+        world.resource_mut::<Replication>().acknowledge(connection, acknowledged);
+
+        // The client goes quiet: deltas against its baseline until the history fills up.
+        for _ in 0..HISTORY_LENGTH {
+            world.get_mut::<Position>(npc).unwrap().x += 1.0;
+            run(&mut world, &mut schedule);
+            let delta = single_snapshot(&mut rx);
+            assert_eq!((delta.baseline, delta.entities.len()), (Some(acknowledged), 1));
+        }
+        world.get_mut::<Position>(npc).unwrap().x += 1.0;
+        run(&mut world, &mut schedule);
+        let snapshot = single_snapshot(&mut rx);
+        assert_eq!((snapshot.baseline, snapshot.entities.len()), (None, 2));
+    }
+}
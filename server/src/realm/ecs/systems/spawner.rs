@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::*;
+use uuid::Uuid;
+
+use crate::realm::{
+    ecs::{
+        components::{Collider, CurrentMap, Identifier, Npc, Position, SpawnedBy, Spawner},
+        resources::Time,
+    },
+    prototype::npc,
+};
+
+pub fn run_spawners(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut spawners: Query<(Entity, &mut Spawner, &Position, &CurrentMap)>,
+    spawned: Query<&SpawnedBy>,
+) {
+    let mut population: HashMap<Entity, u32> = HashMap::default();
+    for spawned_by in spawned.iter() {
+        *population.entry(spawned_by.0).or_default() += 1;
+    }
+    for (entity, mut spawner, position, map) in spawners.iter_mut() {
+        if population.get(&entity).copied().unwrap_or(0) >= spawner.max_population {
+            // The timer only runs while there is room for another NPC.
+            spawner.cooldown_ms = spawner.respawn_ms;
+            continue;
+        }
+        spawner.cooldown_ms -= time.delta_ms;
+        if spawner.cooldown_ms > 0.0 {
+            continue;
+        }
+        spawner.cooldown_ms = spawner.respawn_ms;
+        let Some(prototype) = npc::get(spawner.prototype) else {
+            continue;
+        };
+        commands.spawn((
+            Npc { prototype: prototype.name },
+            Identifier { id: Uuid::new_v4() },
+            Position { x: position.x, y: position.y },
+            Collider { dynamic: true, w: prototype.w, h: prototype.h },
+            map.clone(),
+            SpawnedBy(entity),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn population(world: &mut World) -> usize {
+        world.query::<&Npc>().iter(world).count()
+    }
+
+    #[test]
+    fn respawns_up_to_the_population_cap() {
+        let mut world = World::new();
+        world.insert_resource(Time::new(100.0));
+        world.spawn((
+            Spawner { prototype: "shell_bomy", max_population: 2, respawn_ms: 250.0, cooldown_ms: 0.0 },
+            Position { x: 10.0, y: 20.0 },
+            CurrentMap("map".to_string()),
+        ));
+        let mut schedule = Schedule::default();
+        schedule.add_systems(run_spawners);
+
+        schedule.run(&mut world);
+        assert_eq!(population(&mut world), 1);
+        for _ in 0..3 {
+            schedule.run(&mut world);
+        }
+        assert_eq!(population(&mut world), 2);
+        for _ in 0..10 {
+            schedule.run(&mut world);
+        }
+        assert_eq!(population(&mut world), 2);
+
+        let npc = world.query_filtered::<Entity, With<Npc>>().iter(&world).next().unwrap();
+        world.despawn(npc);
+        schedule.run(&mut world);
+        assert_eq!(population(&mut world), 1);
+        for _ in 0..3 {
+            schedule.run(&mut world);
+        }
+        assert_eq!(population(&mut world), 2);
+    }
+}
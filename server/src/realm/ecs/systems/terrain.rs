@@ -0,0 +1,51 @@
+use bevy_ecs::prelude::*;
+
+use crate::realm::{
+    ecs::components::{Collider, CurrentMap, Position, PreviousPosition},
+    map::MapRegistry,
+};
+
+/// Runs first in the tick, before anything moves.
+pub fn record_previous_positions(mut query: Query<(&Position, &mut PreviousPosition)>) {
+    for (position, mut previous) in query.iter_mut() {
+        previous.x = position.x;
+        previous.y = position.y;
+    }
+}
+
+/// Undoes moves into water, impassable tiles or up and down cliffs. Blocked moves slide along
+/// whichever axis is still free. Entities that were somewhere invalid are moved to the nearest
+/// walkable spot.
+pub fn clamp_to_terrain(
+    maps: Res<MapRegistry>,
+    mut query: Query<(&mut Position, &PreviousPosition, &Collider, &CurrentMap)>,
+) {
+    for (mut position, previous, collider, map) in query.iter_mut() {
+        if !collider.dynamic || (position.x == previous.x && position.y == previous.y) {
+            continue;
+        }
+        let Some(map) = maps.get(&map.0) else {
+            continue;
+        };
+        let from = (previous.x, previous.y);
+        if !map.is_walkable(from.0, from.1, collider.w, collider.h) {
+            if let Some((x, y)) = map.nearest_walkable(position.x, position.y, collider.w, collider.h) {
+                position.x = x;
+                position.y = y;
+            }
+            continue;
+        }
+        let allows = |to: (f32, f32)| map.allows_move(from, to, collider.w, collider.h);
+        if allows((position.x, position.y)) {
+            continue;
+        }
+        if allows((position.x, previous.y)) {
+            position.y = previous.y;
+        } else if allows((previous.x, position.y)) {
+            position.x = previous.x;
+        } else {
+            position.x = previous.x;
+            position.y = previous.y;
+        }
+    }
+}
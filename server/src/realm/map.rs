@@ -0,0 +1,491 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use bevy_ecs::prelude::*;
+use shared::map::WATER_LEVEL_HEIGHT;
+
+/// Largest height difference between neighbouring tiles that can be walked; anything steeper is a
+/// cliff.
+pub const MAX_STEP_HEIGHT: u32 = 2;
+/// Every tile placed on a tile layer with this name blocks movement.
+const COLLISION_LAYER: &str = "collision";
+/// Tileset tiles with this bool property set block movement wherever they are placed.
+const COLLISION_PROPERTY: &str = "collision";
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(io::Error),
+    Tiled(tiled::Error),
+    /// The heightmap does not have one value per tile.
+    HeightmapMismatch {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read map: {err}"),
+            Self::Tiled(err) => write!(f, "failed to parse tilemap: {err}"),
+            Self::HeightmapMismatch { expected, found } => {
+                write!(f, "heightmap has {found} values, expected {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+const DEFAULT_MAX_POPULATION: u32 = 3;
+const DEFAULT_RESPAWN_SECS: f32 = 30.0;
+
+/// Object placed on a map's object layers, in world coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapObject {
+    /// `type="Spawner"`: keeps NPCs of the `spawn` prototype alive around a point.
+    /// `max_population` and `respawn_secs` properties are optional.
+    Spawner {
+        spawn: String,
+        x: f32,
+        y: f32,
+        max_population: u32,
+        respawn_secs: f32,
+    },
+    /// `type="SpawnPoint"`: where players new to the map start.
+    SpawnPoint {
+        x: f32,
+        y: f32,
+    },
+    /// `type="Misc"`: a static prop animated by its `gani`.
+    Misc {
+        gani: String,
+        x: f32,
+        y: f32,
+        w: f32,
+        h: f32,
+    },
+}
+
+/// What the server knows about a map: its dimensions, which tiles can be walked on and the
+/// objects placed on it.
+pub struct MapData {
+    pub name: String,
+    width: u32,
+    height: u32,
+    tile_width: u32,
+    tile_height: u32,
+    heights: Vec<u32>,
+    passable: Vec<bool>,
+    objects: Vec<MapObject>,
+}
+
+impl MapData {
+    /// Loads `<map_path>.tmx` and its `<map_path>.heightmap`.
+    pub fn load(name: &str, map_path: &Path) -> Result<Self, MapError> {
+        let tilemap = tiled::Loader::new()
+            .load_tmx_map(map_path.with_extension("tmx"))
+            .map_err(MapError::Tiled)?;
+        let contents =
+            fs::read_to_string(map_path.with_extension("heightmap")).map_err(MapError::Io)?;
+        Self::from_parts(name, &tilemap, parse_heightmap(&contents))
+    }
+
+    fn from_parts(name: &str, tilemap: &tiled::Map, heights: Vec<u32>) -> Result<Self, MapError> {
+        let expected = tilemap.width as usize * tilemap.height as usize;
+        if heights.len() != expected {
+            return Err(MapError::HeightmapMismatch {
+                expected,
+                found: heights.len(),
+            });
+        }
+        let mut map = Self::from_heights(
+            name,
+            (tilemap.width, tilemap.height),
+            (tilemap.tile_width, tilemap.tile_height),
+            heights,
+        );
+        for (index, passable) in map.passable.iter_mut().enumerate() {
+            let (x, y) = (index % map.width as usize, index / map.width as usize);
+            if is_collision_tile(tilemap, x as i32, y as i32) {
+                *passable = false;
+            }
+        }
+        map.objects = parse_objects(name, tilemap);
+        Ok(map)
+    }
+
+    /// Map without tile collision or objects; water is the only impassable terrain.
+    fn from_heights(name: &str, size: (u32, u32), tile_size: (u32, u32), heights: Vec<u32>) -> Self {
+        let passable = heights
+            .iter()
+            .map(|height| *height >= WATER_LEVEL_HEIGHT)
+            .collect();
+        Self {
+            name: name.to_string(),
+            width: size.0,
+            height: size.1,
+            tile_width: tile_size.0,
+            tile_height: tile_size.1,
+            heights,
+            passable,
+            objects: Vec::new(),
+        }
+    }
+
+    /// Size in tiles.
+    pub fn get_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn get_tile_dimensions(&self) -> (u32, u32) {
+        (self.tile_width, self.tile_height)
+    }
+
+    pub fn get_pixel_size(&self) -> (f32, f32) {
+        (
+            (self.width * self.tile_width) as f32,
+            (self.height * self.tile_height) as f32,
+        )
+    }
+
+    pub fn objects(&self) -> &[MapObject] {
+        &self.objects
+    }
+
+    pub fn get_height(&self, x: u32, y: u32) -> Option<u32> {
+        self.index(x, y).map(|index| self.heights[index])
+    }
+
+    /// Whether the tile can be walked on. Out of bounds tiles never are.
+    pub fn is_passable(&self, x: u32, y: u32) -> bool {
+        self.index(x, y).is_some_and(|index| self.passable[index])
+    }
+
+    /// Whether a box centered on (`x`, `y`) only covers passable tiles.
+    pub fn is_walkable(&self, x: f32, y: f32, w: f32, h: f32) -> bool {
+        let (Some(min), Some(max)) = (
+            self.position_to_tile_coordinates(x - w / 2.0, y - h / 2.0),
+            self.position_to_tile_coordinates(x + w / 2.0 - f32::EPSILON, y + h / 2.0 - f32::EPSILON),
+        ) else {
+            return false;
+        };
+        (min.1..=max.1).all(|tile_y| (min.0..=max.0).all(|tile_x| self.is_passable(tile_x, tile_y)))
+    }
+
+    /// Whether a box can move between two positions: it must end up on walkable tiles, and its
+    /// center must not climb or drop down a cliff.
+    pub fn allows_move(&self, from: (f32, f32), to: (f32, f32), w: f32, h: f32) -> bool {
+        if !self.is_walkable(to.0, to.1, w, h) {
+            return false;
+        }
+        let (Some(from_tile), Some(to_tile)) = (
+            self.position_to_tile_coordinates(from.0, from.1),
+            self.position_to_tile_coordinates(to.0, to.1),
+        ) else {
+            return false;
+        };
+        match (self.get_height(from_tile.0, from_tile.1), self.get_height(to_tile.0, to_tile.1)) {
+            (Some(from_height), Some(to_height)) => from_height.abs_diff(to_height) <= MAX_STEP_HEIGHT,
+            _ => false,
+        }
+    }
+
+    /// Where players new to the map start: its first spawn point, or the walkable spot closest to
+    /// the middle of the map if it has none.
+    pub fn spawn_point(&self, w: f32, h: f32) -> Option<(f32, f32)> {
+        let placed = self.objects.iter().find_map(|object| match object {
+            MapObject::SpawnPoint { x, y } => Some((*x, *y)),
+            _ => None,
+        });
+        let (x, y) = placed.unwrap_or_else(|| {
+            let (width, height) = self.get_pixel_size();
+            (width / 2.0, height / 2.0)
+        });
+        self.nearest_walkable(x, y, w, h)
+    }
+
+    /// Closest position to (`x`, `y`) where a `w` by `h` box is walkable: the position itself if
+    /// it already is, the center of the nearest tile that is otherwise. Tiles are searched in
+    /// growing rings, so the answer is the nearest tile of the first ring that has one.
+    pub fn nearest_walkable(&self, x: f32, y: f32, w: f32, h: f32) -> Option<(f32, f32)> {
+        if self.is_walkable(x, y, w, h) {
+            return Some((x, y));
+        }
+        let (width, height) = self.get_pixel_size();
+        let origin = (
+            (x.clamp(0.0, width - 1.0) as u32 / self.tile_width) as i64,
+            (y.clamp(0.0, height - 1.0) as u32 / self.tile_height) as i64,
+        );
+        let center = |tile_x: i64, tile_y: i64| {
+            (
+                (tile_x as f32 + 0.5) * self.tile_width as f32,
+                (tile_y as f32 + 0.5) * self.tile_height as f32,
+            )
+        };
+        let distance = |(to_x, to_y): (f32, f32)| (to_x - x).powi(2) + (to_y - y).powi(2);
+        for radius in 0..=self.width.max(self.height) as i64 {
+            let ring = (origin.1 - radius..=origin.1 + radius).flat_map(|tile_y| {
+                (origin.0 - radius..=origin.0 + radius)
+                    .filter(move |tile_x| (tile_x - origin.0).abs() == radius || (tile_y - origin.1).abs() == radius)
+                    .map(move |tile_x| (tile_x, tile_y))
+            });
+            let nearest = ring
+                .filter(|(tile_x, tile_y)| *tile_x >= 0 && *tile_y >= 0)
+                .map(|(tile_x, tile_y)| center(tile_x, tile_y))
+                .filter(|(to_x, to_y)| self.is_walkable(*to_x, *to_y, w, h))
+                .min_by(|a, b| distance(*a).total_cmp(&distance(*b)));
+            if nearest.is_some() {
+                return nearest;
+            }
+        }
+        None
+    }
+
+    /// Tile containing a world position, if the position is on the map.
+    pub fn position_to_tile_coordinates(&self, x: f32, y: f32) -> Option<(u32, u32)> {
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+        let tile_x = x as u32 / self.tile_width;
+        let tile_y = y as u32 / self.tile_height;
+        self.index(tile_x, tile_y).map(|_| (tile_x, tile_y))
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some(y as usize * self.width as usize + x as usize)
+        } else {
+            None
+        }
+    }
+}
+
+/// Every map the realm can place entities on, keyed by name (the file stem).
+#[derive(Resource, Default)]
+pub struct MapRegistry {
+    maps: HashMap<String, MapData>,
+}
+
+impl MapRegistry {
+    /// Loads every `.tmx` in `asset_path` that has a `.heightmap` next to it. Maps that fail to
+    /// load are logged and skipped.
+    pub fn load_dir(asset_path: &Path) -> Self {
+        let mut registry = Self::default();
+        let entries = match fs::read_dir(asset_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read map directory [{}]: {e}", asset_path.display());
+                return registry;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "tmx") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !path.with_extension("heightmap").exists() {
+                warn!("Skipping map [{name}] without a heightmap");
+                continue;
+            }
+            match MapData::load(name, &path) {
+                Ok(map) => {
+                    info!("Loaded map [{name}] ({}x{})", map.width, map.height);
+                    registry.insert(map);
+                }
+                Err(e) => error!("Failed to load map [{name}]: {e}"),
+            }
+        }
+        registry
+    }
+
+    pub fn insert(&mut self, map: MapData) {
+        self.maps.insert(map.name.clone(), map);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MapData> {
+        self.maps.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MapData> {
+        self.maps.values()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.maps.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.maps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+}
+
+fn parse_objects(name: &str, tilemap: &tiled::Map) -> Vec<MapObject> {
+    let mut objects = Vec::new();
+    for layer in tilemap.layers() {
+        let Some(object_layer) = layer.as_object_layer() else {
+            continue;
+        };
+        for object in object_layer.objects() {
+            let (w, h) = match object.shape {
+                tiled::ObjectShape::Rect { width, height } => (width, height),
+                _ => (0.0, 0.0),
+            };
+            match object.user_type.as_str() {
+                "Spawner" => {
+                    let Some(spawn) = string_property(&object.properties, "spawn") else {
+                        warn!("Spawner [{}] on [{name}] has no spawn property", object.id());
+                        continue;
+                    };
+                    objects.push(MapObject::Spawner {
+                        spawn,
+                        x: object.x,
+                        y: object.y,
+                        max_population: number_property(&object.properties, "max_population")
+                            .map_or(DEFAULT_MAX_POPULATION, |value| value.max(0.0) as u32),
+                        respawn_secs: number_property(&object.properties, "respawn_secs")
+                            .unwrap_or(DEFAULT_RESPAWN_SECS),
+                    });
+                }
+                "SpawnPoint" => objects.push(MapObject::SpawnPoint { x: object.x, y: object.y }),
+                "Misc" => {
+                    let Some(gani) = string_property(&object.properties, "gani") else {
+                        warn!("Misc object [{}] on [{name}] has no gani property", object.id());
+                        continue;
+                    };
+                    objects.push(MapObject::Misc {
+                        gani,
+                        x: object.x,
+                        y: object.y,
+                        w,
+                        h,
+                    });
+                }
+                other => debug!("Ignoring object [{}] of type [{other}] on [{name}]", object.id()),
+            }
+        }
+    }
+    objects
+}
+
+fn is_collision_tile(tilemap: &tiled::Map, x: i32, y: i32) -> bool {
+    tilemap.layers().any(|layer| {
+        let Some(tile_layer) = layer.as_tile_layer() else {
+            return false;
+        };
+        let Some(layer_tile) = tile_layer.get_tile(x, y) else {
+            return false;
+        };
+        if layer.name == COLLISION_LAYER {
+            return true;
+        }
+        layer_tile.get_tile().is_some_and(|tile| {
+            matches!(tile.properties.get(COLLISION_PROPERTY), Some(tiled::PropertyValue::BoolValue(true)))
+        })
+    })
+}
+
+fn string_property(properties: &tiled::Properties, key: &str) -> Option<String> {
+    match properties.get(key)? {
+        tiled::PropertyValue::StringValue(value) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn number_property(properties: &tiled::Properties, key: &str) -> Option<f32> {
+    match properties.get(key)? {
+        tiled::PropertyValue::IntValue(value) => Some(*value as f32),
+        tiled::PropertyValue::FloatValue(value) => Some(*value),
+        tiled::PropertyValue::StringValue(value) => value.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Heightmaps are comma separated tile heights, one map row per line.
+fn parse_heightmap(contents: &str) -> Vec<u32> {
+    contents
+        .lines()
+        .flat_map(|line| line.split(','))
+        .filter_map(|value| value.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loads_maps_from_a_directory() {
+        let registry = MapRegistry::load_dir(Path::new("fixtures/maps"));
+        let map = registry.get("fixture").unwrap();
+        assert_eq!(map.get_size(), (4, 3));
+        assert_eq!(map.get_pixel_size(), (64.0, 48.0));
+        assert_eq!(map.get_height(0, 0), Some(40));
+        // Water, a tile flagged as collision and a tile on the collision layer.
+        assert!(!map.is_passable(0, 0));
+        assert!(!map.is_passable(1, 1));
+        assert!(!map.is_passable(3, 0));
+        assert!(map.is_passable(1, 0));
+        assert!(!map.is_passable(4, 0));
+        assert_eq!(map.position_to_tile_coordinates(40.0, 17.0), Some((2, 1)));
+        assert_eq!(map.position_to_tile_coordinates(-1.0, 0.0), None);
+        assert_eq!(
+            map.objects()[0],
+            MapObject::Spawner {
+                spawn: "shell_bomy".to_string(),
+                x: 40.0,
+                y: 24.0,
+                max_population: 2,
+                respawn_secs: DEFAULT_RESPAWN_SECS,
+            }
+        );
+        assert_eq!(map.objects().len(), 3);
+        assert_eq!(map.spawn_point(8.0, 8.0), Some((40.0, 40.0)));
+    }
+
+    #[test]
+    fn blocks_water_and_cliffs() {
+        #[rustfmt::skip]
+        let heights = vec![
+            60, 61, 64,
+            60, 40, 60,
+        ];
+        let map = MapData::from_heights("test", (3, 2), (16, 16), heights);
+        assert!(map.allows_move((8.0, 8.0), (24.0, 8.0), 8.0, 8.0));
+        // A step of 3 is a cliff.
+        assert!(!map.allows_move((24.0, 8.0), (40.0, 8.0), 8.0, 8.0));
+        assert!(!map.allows_move((8.0, 24.0), (24.0, 24.0), 8.0, 8.0));
+        // Overlapping the water tile's corner.
+        assert!(!map.is_walkable(16.0, 16.0, 8.0, 8.0));
+        // Hanging off the map.
+        assert!(!map.is_walkable(4.0, 8.0, 16.0, 8.0));
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_walkable_tile() {
+        #[rustfmt::skip]
+        let heights = vec![
+            60, 61, 64,
+            60, 40, 60,
+        ];
+        let map = MapData::from_heights("test", (3, 2), (16, 16), heights);
+        assert_eq!(map.nearest_walkable(10.0, 6.0, 8.0, 8.0), Some((10.0, 6.0)));
+        assert_eq!(map.nearest_walkable(24.0, 26.0, 8.0, 8.0), Some((8.0, 24.0)));
+        assert_eq!(map.nearest_walkable(-100.0, -100.0, 8.0, 8.0), Some((8.0, 8.0)));
+        // No spawn point placed, the middle of the map is water.
+        assert_eq!(map.spawn_point(8.0, 8.0), Some((24.0, 8.0)));
+        let flooded = MapData::from_heights("flooded", (2, 1), (16, 16), vec![40, 40]);
+        assert_eq!(flooded.nearest_walkable(8.0, 8.0, 8.0, 8.0), None);
+    }
+
+    #[test]
+    fn parses_heightmap_rows() {
+        assert_eq!(parse_heightmap("1,2,3\n4,5,6\n"), vec![1, 2, 3, 4, 5, 6]);
+    }
+}
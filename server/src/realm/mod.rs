@@ -1,6 +1,8 @@
 pub mod ecs;
+pub mod map;
 pub mod prototype;
 
 pub mod realm_core;
 pub mod realm_state;
+pub mod tick;
 pub mod types;
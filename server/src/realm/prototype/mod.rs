@@ -1 +1,2 @@
 pub mod item;
+pub mod npc;
@@ -0,0 +1,18 @@
+/// Template NPC entities are created from.
+#[derive(Debug, PartialEq)]
+pub struct NpcPrototype {
+    pub name: &'static str,
+    pub w: f32,
+    pub h: f32,
+}
+
+// TODO: load prototypes from assets once there are more than a handful.
+const NPC_PROTOTYPES: &[NpcPrototype] = &[NpcPrototype {
+    name: "shell_bomy",
+    w: 16.0,
+    h: 16.0,
+}];
+
+pub fn get(name: &str) -> Option<&'static NpcPrototype> {
+    NPC_PROTOTYPES.iter().find(|prototype| prototype.name == name)
+}
@@ -1,98 +1,179 @@
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use shared::core::Core;
-use tokio::{sync::mpsc, task::JoinHandle};
-use tokio_util::sync::CancellationToken;
+use shared::{channel::{self, ChannelConfig, OverflowPolicy, Receiver}, client_messages::FromClient, core::{Core, Reply}, server_messages::FromServer};
+use tokio::{task::JoinHandle, time::Instant};
 use uuid::Uuid;
 
-use crate::{networking_core::ConnectionId, realm::realm_state::RealmState};
+use crate::{networking_core::ConnectionId, persistence::PlayerRecord, realm::{realm_state::RealmState, tick::{OverrunPolicy, TickScheduler, TickStats}}};
+
+/// Name the realm core is supervised under.
+pub const CORE_NAME: &str = "realm";
 
 pub enum RealmEvent {
     PlayerSpawned {
         connection_id: ConnectionId,
         account: String,
+        display_name: String,
         current_map: String,
         entity_identifier: Uuid,
     },
+    /// A message for the client on `connection_id`.
+    Send {
+        connection_id: ConnectionId,
+        message: FromServer,
+    },
+    /// The same message for several clients, serialized once.
+    Multicast {
+        connection_ids: Vec<ConnectionId>,
+        message: FromServer,
+    },
+    /// Player state to persist, sent periodically and when the player leaves.
+    SavePlayer {
+        record: PlayerRecord,
+    },
+    /// Answers `DespawnPlayer` once the player's final `SavePlayer`, if any, was sent.
+    PlayerDespawned {
+        connection_id: ConnectionId,
+    },
 }
 
 pub enum RealmCommand {
     Stop,
-    Tick { elapsed_time_ms: f32 },
+    /// Creates the entity of a freshly logged in account from its saved state, if any.
+    SpawnPlayer {
+        connection_id: ConnectionId,
+        account: String,
+        record: Option<PlayerRecord>,
+    },
+    /// Saves and removes the entity of the player that was on `connection_id`.
+    DespawnPlayer {
+        connection_id: ConnectionId,
+        entity_identifier: Uuid,
+    },
+    /// Timing of the recent ticks, for monitoring.
+    GetTickStats {
+        reply: Reply<TickStats>,
+    },
+    /// Current state of every player, for a final save before shutting down.
+    GetPlayerRecords {
+        reply: Reply<Vec<PlayerRecord>>,
+    },
+    SetDisplayName {
+        entity_identifier: Uuid,
+        display_name: String,
+    },
+    /// A client message routed to the realm, on behalf of the sender's player entity.
+    ClientMessage {
+        connection_id: ConnectionId,
+        entity_identifier: Uuid,
+        message: FromClient,
+    },
 }
 
-#[derive(Default)]
-pub struct RealmCore {}
+pub struct RealmCore {
+    command_channel: ChannelConfig,
+    asset_path: Option<String>,
+    tick_length: Duration,
+    overrun_policy: OverrunPolicy,
+}
+
+impl Default for RealmCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl RealmCore {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            command_channel: ChannelConfig::bounded(1024, OverflowPolicy::Block),
+            asset_path: None,
+            tick_length: Duration::from_millis(50),
+            overrun_policy: OverrunPolicy::default(),
+        }
+    }
+
+    pub fn with_command_channel(mut self, commands: ChannelConfig) -> Self {
+        self.command_channel = commands;
+        self
+    }
+
+    /// Directory the realm loads its maps from.
+    pub fn with_asset_path(mut self, asset_path: impl Into<String>) -> Self {
+        self.asset_path = Some(asset_path.into());
+        self
+    }
+
+    /// Time between two ticks of the world.
+    pub fn with_tick_length(mut self, tick_length: Duration) -> Self {
+        self.tick_length = tick_length;
+        self
+    }
+
+    /// What to do with the ticks missed when the simulation falls behind.
+    pub fn with_overrun_policy(mut self, policy: OverrunPolicy) -> Self {
+        self.overrun_policy = policy;
+        self
     }
 
     pub fn start(&mut self) -> Core<RealmCommand, RealmEvent> {
         info!("Starting Realm Core");
-        let (tx, rx) = mpsc::unbounded_channel();
-        let (event_tx, event_rx) = mpsc::unbounded_channel();
-        let cancellation_token = CancellationToken::new();
-        let state = RealmState::new(None, event_tx.clone());
-        let tick_handle = RealmCore::tick_loop(tx.clone(), cancellation_token.clone());
-        let handle = RealmCore::control_loop(rx, state, tick_handle, cancellation_token.clone());
+        let (tx, rx) = channel::channel(self.command_channel);
+        // Events are sent from synchronous ECS systems, which cannot wait for room.
+        let (event_tx, event_rx) = channel::unbounded_channel();
+        let state = RealmState::new(self.asset_path.as_deref(), self.tick_length, event_tx.clone());
+        let scheduler = TickScheduler::new(self.tick_length, self.overrun_policy, Instant::now());
+        let handle = RealmCore::control_loop(rx, state, scheduler);
         Core::new(tx.clone(), handle).with_events(event_rx)
     }
 
+    /// Runs the ticks on a fixed timestep, handling commands while waiting for the next one.
     fn control_loop(
-        mut rx: mpsc::UnboundedReceiver<RealmCommand>,
+        mut rx: Receiver<RealmCommand>,
         mut state: RealmState,
-        tick_handle: JoinHandle<()>,
-        cancellation_token: CancellationToken,
+        mut scheduler: TickScheduler,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             loop {
-                match rx.recv().await {
-                    Some(command) => match command {
-                        RealmCommand::Stop => {
-                            info!("Realm Core: Stopping");
-                            cancellation_token.cancel();
-                            let _ = tick_handle.await;
-                            break;
-                        }
-                        RealmCommand::Tick { elapsed_time_ms } => {
-                            state.tick(elapsed_time_ms);
+                tokio::select! {
+                    // Ticks come first, so a flood of commands cannot stall the world.
+                    biased;
+                    _ = tokio::time::sleep_until(scheduler.next_tick()) => {
+                        for _ in 0..scheduler.due(Instant::now()) {
+                            let started = Instant::now();
+                            state.tick();
+                            scheduler.record(started.elapsed());
                         }
-                    },
-                    None => {
-                        warn!("RealmCommand closed channel");
-                        break;
                     }
-                }
-            }
-        })
-    }
-
-    fn tick_loop(
-        tx: mpsc::UnboundedSender<RealmCommand>,
-        cancellation_token: CancellationToken,
-    ) -> JoinHandle<()> {
-        let tick_length = Duration::from_millis(50);
-        let mut last_tick = Instant::now();
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    _ = cancellation_token.cancelled() => {
-                        info!("Tick loop closing");
-                        break;
-                    },
-                    else => {
-                        let now = Instant::now();
-                        if now - last_tick >= tick_length {
-                            let elapsed_time_ms = (now - last_tick).as_millis() as f32;
-                            last_tick = now;
-                            if let Err(e) = tx.send(RealmCommand::Tick{ elapsed_time_ms }) {
-                                warn!("Realm Command channel closed: [{e}]");
+                    command = rx.recv() => match command {
+                        Some(command) => match command {
+                            RealmCommand::Stop => {
+                                info!("Realm Core: Stopping");
                                 break;
                             }
+                            RealmCommand::SpawnPlayer { connection_id, account, record } => {
+                                state.spawn_player(connection_id, account, record);
+                            }
+                            RealmCommand::DespawnPlayer { connection_id, entity_identifier } => {
+                                state.despawn_player(connection_id, entity_identifier);
+                            }
+                            RealmCommand::GetTickStats { reply } => {
+                                reply.send(scheduler.stats());
+                            }
+                            RealmCommand::GetPlayerRecords { reply } => {
+                                reply.send(state.player_records());
+                            }
+                            RealmCommand::SetDisplayName { entity_identifier, display_name } => {
+                                state.set_display_name(entity_identifier, display_name);
+                            }
+                            RealmCommand::ClientMessage { connection_id, entity_identifier, message } => {
+                                state.handle_client_message(connection_id, entity_identifier, message);
+                            }
+                        },
+                        None => {
+                            warn!("RealmCommand closed channel");
+                            break;
                         }
-                        tokio::time::sleep(Duration::from_millis(1)).await;
                     }
                 }
             }
@@ -1,33 +1,282 @@
-use bevy_ecs::{schedule::Schedule, world::World};
-use tokio::sync::mpsc;
+use std::{path::Path, time::Duration};
 
-use crate::realm::{realm_core::RealmEvent, ecs::resources::{ElapsedTimeMs, RealmEventSender}};
+use bevy_ecs::{message::{MessageRegistry, message_update_system}, prelude::*};
+use shared::{channel::Sender, client_messages::FromClient};
+use uuid::Uuid;
+
+use crate::{networking_core::ConnectionId, persistence::PlayerRecord, realm::{map::{MapObject, MapRegistry}, prototype::npc, realm_core::RealmEvent, ecs::{components::{Account, Animation, Collider, Connection, CurrentMap, DisplayName, Gani, Identifier, Inventory, MoveState, Player, Position, Spawner, Speed}, messages::{CollisionEnded, CollisionStarted, EnteredView, LeftView}, resources::{ActiveCollisions, RealmEventSender, Time}, systems::{collision::{CollisionGrids, resolve_collisions}, interest::{Interest, update_interest}, movement::{IDLE_ANIMATION, apply_movement, send_move_acks}, replication::{Replication, replicate}, spawner::run_spawners, terrain::{clamp_to_terrain, record_previous_positions}}}}};
+
+/// Map new players start on.
+const DEFAULT_MAP: &str = "offlinetutorial";
+const PLAYER_SIZE: f32 = 16.0;
+/// Pixels per second, six tiles.
+const PLAYER_SPEED: f32 = 96.0;
+/// How often every player is snapshotted for persistence while playing.
+const AUTOSAVE_INTERVAL_MS: f32 = 60_000.0;
 
 pub struct RealmState {
     pub world: World,
     pub systems: Schedule,
+    since_autosave_ms: f32,
 }
 
 impl RealmState {
-    pub fn new(_asset_path: Option<&str>, event_tx: mpsc::UnboundedSender<RealmEvent>) -> Self {
+    pub fn new(asset_path: Option<&str>, tick_length: Duration, event_tx: Sender<RealmEvent>) -> Self {
         let mut world = World::new();
 
+        let maps = match asset_path {
+            Some(asset_path) => MapRegistry::load_dir(Path::new(asset_path)),
+            None => MapRegistry::default(),
+        };
+        info!("Realm has [{}] maps", maps.len());
+        RealmState::spawn_map_objects(&mut world, &maps);
+        world.insert_resource(maps);
+
         world.insert_resource(RealmEventSender(event_tx));
-        world.insert_resource(ElapsedTimeMs(0.0));
+        world.insert_resource(Time::new(tick_length.as_secs_f32() * 1000.0));
+
+        world.init_resource::<ActiveCollisions>();
+        world.init_resource::<CollisionGrids>();
+        world.init_resource::<Interest>();
+        world.init_resource::<Replication>();
+        MessageRegistry::register_message::<CollisionStarted>(&mut world);
+        MessageRegistry::register_message::<CollisionEnded>(&mut world);
+        MessageRegistry::register_message::<EnteredView>(&mut world);
+        MessageRegistry::register_message::<LeftView>(&mut world);
 
-        let mut _systems= Schedule::default();
+        let mut systems = Schedule::default();
+        // Messages are double buffered, so readers see them until the end of the next tick.
+        systems.add_systems(
+            (
+                message_update_system,
+                record_previous_positions,
+                run_spawners,
+                apply_movement,
+                resolve_collisions,
+                clamp_to_terrain,
+                send_move_acks,
+                update_interest,
+                replicate,
+            )
+                .chain(),
+        );
 
         Self {
             world,
-            systems: _systems,
+            systems,
+            since_autosave_ms: 0.0,
         }
     }
 
-    pub fn tick(&mut self, elapsed_time_ms: f32) {
-        self.world
-            .get_resource_mut::<ElapsedTimeMs>()
-            .unwrap()
-            .0 = elapsed_time_ms;
+    /// Creates the spawners and props placed on the maps' object layers.
+    fn spawn_map_objects(world: &mut World, maps: &MapRegistry) {
+        for map in maps.iter() {
+            for object in map.objects() {
+                match object {
+                    MapObject::Spawner { spawn, x, y, max_population, respawn_secs } => {
+                        let Some(prototype) = npc::get(spawn) else {
+                            warn!("Spawner on [{}] uses unknown prototype [{spawn}]", map.name);
+                            continue;
+                        };
+                        world.spawn((
+                            Spawner {
+                                prototype: prototype.name,
+                                max_population: *max_population,
+                                respawn_ms: respawn_secs * 1000.0,
+                                cooldown_ms: 0.0,
+                            },
+                            Position { x: *x, y: *y },
+                            CurrentMap(map.name.clone()),
+                        ));
+                    }
+                    MapObject::Misc { gani, x, y, w, h } => {
+                        world.spawn((
+                            Gani(gani.clone()),
+                            Identifier { id: Uuid::new_v4() },
+                            Position { x: x + w / 2.0, y: y + h / 2.0 },
+                            Collider { dynamic: false, w: *w, h: *h },
+                            CurrentMap(map.name.clone()),
+                        ));
+                    }
+                    MapObject::SpawnPoint { .. } => {}
+                }
+            }
+        }
+    }
+
+    /// Runs the systems for one fixed step.
+    pub fn tick(&mut self) {
+        let mut time = self.world.resource_mut::<Time>();
+        time.advance();
+        let delta_ms = time.delta_ms;
         self.systems.run(&mut self.world);
+        self.since_autosave_ms += delta_ms;
+        if self.since_autosave_ms >= AUTOSAVE_INTERVAL_MS {
+            self.since_autosave_ms = 0.0;
+            self.save_players();
+        }
+    }
+
+    /// Spawns the entity of a logged in account, restoring `record` if it played before.
+    pub fn spawn_player(
+        &mut self,
+        connection_id: ConnectionId,
+        account: String,
+        record: Option<PlayerRecord>,
+    ) {
+        let maps = self.world.resource::<MapRegistry>();
+        let spawn_point = maps
+            .get(DEFAULT_MAP)
+            .and_then(|map| map.spawn_point(PLAYER_SIZE, PLAYER_SIZE))
+            .unwrap_or((0.0, 0.0));
+        let mut record = record.unwrap_or_else(|| PlayerRecord {
+            display_name: account.clone(),
+            account: account.clone(),
+            current_map: DEFAULT_MAP.to_string(),
+            x: spawn_point.0,
+            y: spawn_point.1,
+            inventory: Vec::new(),
+        });
+        match maps.get(&record.current_map) {
+            // The map may have changed under a saved position since it was saved.
+            Some(map) => {
+                if let Some((x, y)) = map.nearest_walkable(record.x, record.y, PLAYER_SIZE, PLAYER_SIZE)
+                    && (x, y) != (record.x, record.y)
+                {
+                    warn!("Player [{account}] was stuck on [{}], moving to [{x}, {y}]", record.current_map);
+                    record.x = x;
+                    record.y = y;
+                }
+            }
+            None if !maps.is_empty() => {
+                warn!("Player [{account}] was on unknown map [{}], moving to [{DEFAULT_MAP}]", record.current_map);
+                record.current_map = DEFAULT_MAP.to_string();
+                record.x = spawn_point.0;
+                record.y = spawn_point.1;
+            }
+            None => {}
+        }
+        let entity_identifier = Uuid::new_v4();
+        self.world.spawn((
+            Player,
+            Identifier { id: entity_identifier },
+            Account(record.account),
+            DisplayName(record.display_name.clone()),
+            Position { x: record.x, y: record.y },
+            CurrentMap(record.current_map.clone()),
+            Inventory(record.inventory),
+            Collider { dynamic: true, w: PLAYER_SIZE, h: PLAYER_SIZE },
+            Connection(connection_id),
+            Speed(PLAYER_SPEED),
+            MoveState::default(),
+            Animation(IDLE_ANIMATION),
+        ));
+        info!("Spawned player [{account}] as [{entity_identifier}] on [{}]", record.current_map);
+        self.send_event(RealmEvent::PlayerSpawned {
+            connection_id,
+            account,
+            display_name: record.display_name,
+            current_map: record.current_map,
+            entity_identifier,
+        });
+    }
+
+    /// Removes a player entity, handing its final state over for persistence.
+    pub fn despawn_player(&mut self, connection_id: ConnectionId, entity_identifier: Uuid) {
+        match self.find_player(entity_identifier) {
+            Some(entity) => {
+                if let Some(record) = self.player_record(entity) {
+                    self.send_event(RealmEvent::SavePlayer { record });
+                }
+                self.world.despawn(entity);
+                info!("Despawned player [{entity_identifier}]");
+            }
+            None => warn!("No player entity for [{entity_identifier}]"),
+        }
+        // Sent either way, the nexus holds the account until it hears back.
+        self.send_event(RealmEvent::PlayerDespawned { connection_id });
+    }
+
+    pub fn set_display_name(&mut self, entity_identifier: Uuid, display_name: String) {
+        match self.find_player(entity_identifier) {
+            Some(entity) => {
+                self.world.entity_mut(entity).insert(DisplayName(display_name));
+            }
+            None => warn!("No player entity for [{entity_identifier}]"),
+        }
+    }
+
+    fn save_players(&mut self) {
+        let records = self.player_records();
+        debug!("Autosaving [{}] players", records.len());
+        for record in records {
+            self.send_event(RealmEvent::SavePlayer { record });
+        }
+    }
+
+    /// Persistable state of every player in the world.
+    pub fn player_records(&mut self) -> Vec<PlayerRecord> {
+        let mut query = self.world.query_filtered::<Entity, With<Player>>();
+        let entities: Vec<Entity> = query.iter(&self.world).collect();
+        entities.into_iter().filter_map(|entity| self.player_record(entity)).collect()
+    }
+
+    fn find_player(&mut self, entity_identifier: Uuid) -> Option<Entity> {
+        let mut query = self.world.query_filtered::<(Entity, &Identifier), With<Player>>();
+        query
+            .iter(&self.world)
+            .find(|(_, identifier)| identifier.id == entity_identifier)
+            .map(|(entity, _)| entity)
+    }
+
+    fn player_record(&self, entity: Entity) -> Option<PlayerRecord> {
+        let entity = self.world.get_entity(entity).ok()?;
+        let position = entity.get::<Position>()?;
+        Some(PlayerRecord {
+            account: entity.get::<Account>()?.0.clone(),
+            display_name: entity.get::<DisplayName>()?.0.clone(),
+            current_map: entity.get::<CurrentMap>()?.0.clone(),
+            x: position.x,
+            y: position.y,
+            inventory: entity.get::<Inventory>().map(|inventory| inventory.0.clone()).unwrap_or_default(),
+        })
+    }
+
+    fn send_event(&self, event: RealmEvent) {
+        if let Err(e) = self.world.resource::<RealmEventSender>().0.try_send(event) {
+            warn!("Failed to send RealmEvent: [{e}]");
+        }
+    }
+
+    pub fn handle_client_message(
+        &mut self,
+        connection_id: ConnectionId,
+        entity_identifier: Uuid,
+        message: FromClient,
+    ) {
+        match message {
+            FromClient::MoveInput(input) => {
+                let Some(entity) = self.find_player(entity_identifier) else {
+                    warn!("No player entity for [{entity_identifier}]");
+                    return;
+                };
+                let now_ms = self.world.resource::<Time>().elapsed_ms;
+                if let Some(mut state) = self.world.get_mut::<MoveState>(entity)
+                    && let Err(rejection) = state.accept(&input, now_ms)
+                {
+                    debug!("Dropped MoveInput [{}] from [{connection_id:?}]: {rejection}", input.sequence);
+                }
+            }
+            FromClient::SnapshotAck(ack) => {
+                self.world.resource_mut::<Replication>().acknowledge(connection_id, ack.tick);
+            }
+            message @ (FromClient::Handshake(_)
+            | FromClient::Login(_)
+            | FromClient::Register(_)
+            | FromClient::Ping(_)
+            | FromClient::Pong(_)) => {
+                warn!("Unhandled message from [{connection_id:?}] for entity [{entity_identifier}]: [{message:?}]");
+            }
+        }
     }
 }
@@ -0,0 +1,156 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+/// Tick durations kept for the mean and p99, a minute at the default 20 ticks per second.
+const TIMING_WINDOW: usize = 1200;
+
+/// What the scheduler does once ticks fall behind the wall clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrunPolicy {
+    /// Runs the missed ticks back to back, at most `max_ticks` at once, so the simulation keeps
+    /// pace with the wall clock. Ticks beyond that are skipped.
+    CatchUp { max_ticks: u32 },
+    /// Runs a single tick and drops the missed ones, letting the simulation fall behind.
+    Skip,
+}
+
+impl Default for OverrunPolicy {
+    fn default() -> Self {
+        Self::CatchUp { max_ticks: 5 }
+    }
+}
+
+/// Timing of the realm's recent ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TickStats {
+    /// Ticks run since the realm started.
+    pub ticks: u64,
+    pub mean: Duration,
+    pub p99: Duration,
+    /// Ticks that took longer than the tick length to run.
+    pub overruns: u64,
+    /// Ticks dropped because the realm fell too far behind.
+    pub skipped: u64,
+}
+
+/// Fixed timestep schedule of the realm's ticks.
+pub struct TickScheduler {
+    tick_length: Duration,
+    policy: OverrunPolicy,
+    next_tick: Instant,
+    durations: VecDeque<Duration>,
+    ticks: u64,
+    overruns: u64,
+    skipped: u64,
+}
+
+impl TickScheduler {
+    pub fn new(tick_length: Duration, policy: OverrunPolicy, now: Instant) -> Self {
+        Self {
+            tick_length,
+            policy,
+            next_tick: now + tick_length,
+            durations: VecDeque::with_capacity(TIMING_WINDOW),
+            ticks: 0,
+            overruns: 0,
+            skipped: 0,
+        }
+    }
+
+    /// When the next tick is due.
+    pub fn next_tick(&self) -> Instant {
+        self.next_tick
+    }
+
+    /// How many ticks to run back to back at `now`, moving the schedule past every tick that
+    /// was due. Ticks stay on the original grid, late ones do not push the later ones back.
+    pub fn due(&mut self, now: Instant) -> u32 {
+        if now < self.next_tick {
+            return 0;
+        }
+        let late = (now - self.next_tick).as_nanos() / self.tick_length.as_nanos();
+        let due = u32::try_from(late).unwrap_or(u32::MAX).saturating_add(1);
+        let run = match self.policy {
+            OverrunPolicy::CatchUp { max_ticks } => due.min(max_ticks.max(1)),
+            OverrunPolicy::Skip => 1,
+        };
+        self.skipped += u64::from(due - run);
+        self.next_tick += self.tick_length * due;
+        run
+    }
+
+    /// Records how long a tick took to run.
+    pub fn record(&mut self, duration: Duration) {
+        self.ticks += 1;
+        if duration > self.tick_length {
+            self.overruns += 1;
+        }
+        if self.durations.len() == TIMING_WINDOW {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(duration);
+    }
+
+    pub fn stats(&self) -> TickStats {
+        let mut durations: Vec<Duration> = self.durations.iter().copied().collect();
+        durations.sort_unstable();
+        let mean = match durations.len() {
+            0 => Duration::ZERO,
+            len => durations.iter().sum::<Duration>() / len as u32,
+        };
+        let p99 = match durations.len() {
+            0 => Duration::ZERO,
+            len => durations[(len * 99).div_ceil(100) - 1],
+        };
+        TickStats {
+            ticks: self.ticks,
+            mean,
+            p99,
+            overruns: self.overruns,
+            skipped: self.skipped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn catches_up_on_missed_ticks() {
+        let start = Instant::now();
+        let mut scheduler = TickScheduler::new(TICK, OverrunPolicy::CatchUp { max_ticks: 3 }, start);
+        assert_eq!(scheduler.due(start + Duration::from_millis(49)), 0);
+        assert_eq!(scheduler.due(start + Duration::from_millis(60)), 1);
+        assert_eq!(scheduler.next_tick(), start + TICK * 2);
+        // Seven ticks were due, only three are run.
+        assert_eq!(scheduler.due(start + Duration::from_millis(410)), 3);
+        assert_eq!(scheduler.next_tick(), start + TICK * 9);
+        assert_eq!(scheduler.stats().skipped, 4);
+    }
+
+    #[test]
+    fn skips_missed_ticks() {
+        let start = Instant::now();
+        let mut scheduler = TickScheduler::new(TICK, OverrunPolicy::Skip, start);
+        assert_eq!(scheduler.due(start + Duration::from_millis(160)), 1);
+        assert_eq!(scheduler.next_tick(), start + TICK * 4);
+        assert_eq!(scheduler.stats().skipped, 2);
+    }
+
+    #[test]
+    fn summarizes_tick_durations() {
+        let mut scheduler = TickScheduler::new(TICK, OverrunPolicy::default(), Instant::now());
+        for ms in 1..=100 {
+            scheduler.record(Duration::from_millis(ms));
+        }
+        let stats = scheduler.stats();
+        assert_eq!(stats.ticks, 100);
+        assert_eq!(stats.mean, Duration::from_micros(50_500));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+        assert_eq!(stats.overruns, 50);
+    }
+}
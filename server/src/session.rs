@@ -3,18 +3,54 @@ use uuid::Uuid;
 #[allow(dead_code)]
 pub enum SessionState {
     AwaitingLogin,
+    /// Credentials are being checked. The session does not hold the account until they match,
+    /// so a wrong password cannot tell whether it is in use.
+    Verifying,
+    /// Credentials were accepted; waiting for the realm to spawn the player.
+    LoggingIn {
+        account: String,
+    },
     Playing {
         entity_identifier: Uuid,
         account: String,
         display_name: String,
         current_map: String,
     },
+    /// The connection closed and the player is being saved. The account stays taken until the
+    /// save is queued, so logging back in cannot load the character from before it.
+    LoggingOut {
+        account: String,
+    },
 }
 
 impl SessionState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::AwaitingLogin => "AwaitingLogin",
+            Self::Verifying => "Verifying",
+            Self::LoggingIn { .. } => "LoggingIn",
+            Self::Playing { .. } => "Playing",
+            Self::LoggingOut { .. } => "LoggingOut",
+        }
+    }
+
     pub fn get_account(&self) -> Option<&String> {
-        if let Self::Playing { account, .. } = self {
-            return Some(account);
+        match self {
+            Self::LoggingIn { account } | Self::Playing { account, .. } | Self::LoggingOut { account } => Some(account),
+            Self::AwaitingLogin | Self::Verifying => None,
+        }
+    }
+
+    pub fn get_entity_identifier(&self) -> Option<&Uuid> {
+        if let Self::Playing { entity_identifier, .. } = self {
+            return Some(entity_identifier);
+        }
+        None
+    }
+
+    pub fn get_current_map(&self) -> Option<&String> {
+        if let Self::Playing { current_map, .. } = self {
+            return Some(current_map);
         }
         None
     }
@@ -0,0 +1,90 @@
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use shared::collision::Grid;
+
+const ENTITIES: u32 = 5000;
+const WORLD_SIZE: f32 = 3072.0;
+const CELL_SIZE: f32 = 32.0;
+const SIZE: f32 = 16.0;
+
+/// Small deterministic generator so runs are comparable without pulling in `rand`.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn positions() -> Vec<(f32, f32)> {
+    let mut rng = Lcg(7);
+    (0..ENTITIES)
+        .map(|_| (rng.next() * WORLD_SIZE, rng.next() * WORLD_SIZE))
+        .collect()
+}
+
+/// Moves every `stride`-th entity a couple of pixels, like a tick where only some walk.
+fn step(positions: &mut [(f32, f32)], stride: usize) -> Vec<u32> {
+    let mut moved = Vec::new();
+    for (id, position) in positions.iter_mut().enumerate().step_by(stride) {
+        position.0 = (position.0 + 2.0) % WORLD_SIZE;
+        moved.push(id as u32);
+    }
+    moved
+}
+
+fn bench_grid(c: &mut Criterion) {
+    for (label, stride) in [("all moving", 1), ("10% moving", 10)] {
+        let mut group = c.benchmark_group(format!("grid {ENTITIES} entities, {label}"));
+
+        let mut state = positions();
+        let mut grid = Grid::new(CELL_SIZE);
+        group.bench_function("rebuild", |b| {
+            b.iter(|| {
+                step(&mut state, stride);
+                grid.clear();
+                for (id, (x, y)) in state.iter().enumerate() {
+                    grid.insert(id as u32, *x, *y, SIZE, SIZE);
+                }
+                black_box(grid.len())
+            })
+        });
+
+        let mut state = positions();
+        let mut grid = Grid::new(CELL_SIZE);
+        for (id, (x, y)) in state.iter().enumerate() {
+            grid.insert(id as u32, *x, *y, SIZE, SIZE);
+        }
+        group.bench_function("update", |b| {
+            b.iter(|| {
+                for id in step(&mut state, stride) {
+                    let (x, y) = state[id as usize];
+                    grid.update(id, x, y, SIZE, SIZE);
+                }
+                black_box(grid.len())
+            })
+        });
+
+        let mut state = positions();
+        let mut grid = Grid::new(CELL_SIZE);
+        for (id, (x, y)) in state.iter().enumerate() {
+            grid.insert(id as u32, *x, *y, SIZE, SIZE);
+        }
+        group.bench_function("dirty flush", |b| {
+            b.iter(|| {
+                for id in step(&mut state, stride) {
+                    let (x, y) = state[id as usize];
+                    grid.mark_dirty(id, x, y, SIZE, SIZE);
+                }
+                black_box(grid.flush())
+            })
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_grid);
+criterion_main!(benches);
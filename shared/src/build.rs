@@ -4,13 +4,13 @@ mod gk_shared_built_info {
 }
 
 pub fn get_version() -> Option<String> {
-    if let Some(hash) = gk_shared_built_info::GIT_VERSION {
-        if let Some(dirty) = gk_shared_built_info::GIT_DIRTY {
-            if dirty {
-                return Some(String::from(hash) + "+");
-            }
-            return Some(String::from(hash));
+    if let Some(hash) = gk_shared_built_info::GIT_VERSION
+        && let Some(dirty) = gk_shared_built_info::GIT_DIRTY
+    {
+        if dirty {
+            return Some(String::from(hash) + "+");
         }
+        return Some(String::from(hash));
     }
     None
 }
@@ -0,0 +1,389 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use tokio::sync::Notify;
+
+/// What a bounded channel does with a message sent while it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// `send` waits for room, `try_send` fails with `SendError::Full`.
+    Block,
+    /// The oldest queued message is discarded to make room.
+    DropOldest,
+    /// The message being sent is discarded.
+    DropNewest,
+    /// The sending handle is cut off and every later send through it fails.
+    DisconnectSender,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelConfig {
+    pub capacity: Option<usize>,
+    pub policy: OverflowPolicy,
+}
+
+impl ChannelConfig {
+    pub fn unbounded() -> Self {
+        Self {
+            capacity: None,
+            policy: OverflowPolicy::Block,
+        }
+    }
+
+    pub fn bounded(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity: Some(capacity.max(1)),
+            policy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub capacity: Option<usize>,
+    /// Deepest the queue has been since it was created.
+    pub high_water: usize,
+    /// Messages discarded by the overflow policy.
+    pub dropped: u64,
+}
+
+impl QueueStats {
+    /// Fraction of the capacity in use, `0.0` for unbounded queues.
+    pub fn saturation(&self) -> f32 {
+        match self.capacity {
+            Some(capacity) => self.depth as f32 / capacity as f32,
+            None => 0.0,
+        }
+    }
+}
+
+pub enum SendError<T> {
+    /// The receiver is gone.
+    Closed(T),
+    /// The channel is full and its policy is `Block`.
+    Full(T),
+    /// This sender was disconnected by the `DisconnectSender` policy.
+    Disconnected(T),
+}
+
+impl<T> SendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Closed(value) | Self::Full(value) | Self::Disconnected(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed(_) => f.write_str("Closed(..)"),
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed(_) => write!(f, "channel closed"),
+            Self::Full(_) => write!(f, "channel full"),
+            Self::Disconnected(_) => write!(f, "sender disconnected after overflowing the channel"),
+        }
+    }
+}
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    config: ChannelConfig,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    /// Wakes the receiver when a message arrives or the last sender leaves.
+    recv_notify: Notify,
+    /// Wakes blocked senders when room frees up or the receiver leaves.
+    send_notify: Notify,
+    high_water: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl<T> Shared<T> {
+    fn push(&self, mut queue: MutexGuard<'_, VecDeque<T>>, value: T) {
+        queue.push_back(value);
+        self.high_water.fetch_max(queue.len(), Ordering::Relaxed);
+        drop(queue);
+        self.recv_notify.notify_one();
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.queue.lock().unwrap().len(),
+            capacity: self.config.capacity,
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Creates a channel that applies `config` when it fills up.
+pub fn channel<T>(config: ChannelConfig) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        config,
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        recv_notify: Notify::new(),
+        send_notify: Notify::new(),
+        high_water: AtomicUsize::new(0),
+        dropped: AtomicU64::new(0),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+            disconnected: AtomicBool::new(false),
+        },
+        Receiver { shared },
+    )
+}
+
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    channel(ChannelConfig::unbounded())
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+    disconnected: AtomicBool,
+}
+
+impl<T> Sender<T> {
+    /// Queues `value`, waiting for room if the channel is full and its policy is `Block`.
+    pub async fn send(&self, mut value: T) -> Result<(), SendError<T>> {
+        loop {
+            // Register interest before checking so a concurrent `recv` cannot slip between the
+            // check and the wait.
+            let notified = self.shared.send_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            match self.try_send(value) {
+                Err(SendError::Full(returned)) => {
+                    value = returned;
+                    notified.await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Queues `value` without waiting. Fails with `SendError::Full` instead of blocking.
+    pub fn try_send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.disconnected.load(Ordering::Relaxed) {
+            return Err(SendError::Disconnected(value));
+        }
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(SendError::Closed(value));
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+        if let Some(capacity) = self.shared.config.capacity
+            && queue.len() >= capacity
+        {
+            match self.shared.config.policy {
+                OverflowPolicy::Block => return Err(SendError::Full(value)),
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::DropNewest => {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                OverflowPolicy::DisconnectSender => {
+                    self.disconnected.store(true, Ordering::Relaxed);
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Err(SendError::Disconnected(value));
+                }
+            }
+        }
+        self.shared.push(queue, value);
+        Ok(())
+    }
+
+    /// Queues `value` regardless of capacity and of this handle being disconnected. Meant for
+    /// the few bookkeeping messages that must not be lost, such as a connection going away.
+    pub fn force_send(&self, value: T) -> Result<(), SendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(SendError::Closed(value));
+        }
+        let queue = self.shared.queue.lock().unwrap();
+        self.shared.push(queue, value);
+        Ok(())
+    }
+
+    pub fn config(&self) -> ChannelConfig {
+        self.shared.config
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.stats()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    /// Clones start connected even if this handle was cut off by `DisconnectSender`.
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.recv_notify.notify_one();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").field("stats", &self.stats()).finish()
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next message. Returns `None` once every sender is gone and the queue is
+    /// drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            if let Some(value) = self.try_recv() {
+                return Some(value);
+            }
+            if self.shared.senders.load(Ordering::Acquire) == 0 {
+                // A message may have been queued right before the last sender left.
+                return self.try_recv();
+            }
+            self.shared.recv_notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.shared.queue.lock().unwrap().pop_front();
+        if value.is_some() {
+            self.shared.send_notify.notify_one();
+        }
+        value
+    }
+
+    pub fn config(&self) -> ChannelConfig {
+        self.shared.config
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.stats()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        self.shared.queue.lock().unwrap().clear();
+        self.shared.send_notify.notify_waiters();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").field("stats", &self.stats()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest_messages() {
+        let (tx, mut rx) = channel(ChannelConfig::bounded(2, OverflowPolicy::DropOldest));
+        for i in 0..4 {
+            tx.try_send(i).unwrap();
+        }
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(tx.stats().dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_oldest_messages() {
+        let (tx, mut rx) = channel(ChannelConfig::bounded(2, OverflowPolicy::DropNewest));
+        for i in 0..4 {
+            tx.try_send(i).unwrap();
+        }
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.stats().dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_sender_only_cuts_off_the_overflowing_handle() {
+        let (tx, mut rx) = channel(ChannelConfig::bounded(1, OverflowPolicy::DisconnectSender));
+        let other = tx.clone();
+        tx.try_send(1).unwrap();
+        assert!(matches!(tx.try_send(2), Err(SendError::Disconnected(2))));
+        assert_eq!(rx.recv().await, Some(1));
+        assert!(matches!(tx.try_send(3), Err(SendError::Disconnected(3))));
+        other.try_send(4).unwrap();
+        assert_eq!(rx.recv().await, Some(4));
+        tx.force_send(5).unwrap();
+        assert_eq!(rx.recv().await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (tx, mut rx) = channel(ChannelConfig::bounded(1, OverflowPolicy::Block));
+        tx.send(1).await.unwrap();
+        assert!(matches!(tx.try_send(2), Err(SendError::Full(2))));
+        let sender = tokio::spawn(async move { tx.send(2).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!sender.is_finished());
+        assert_eq!(rx.recv().await, Some(1));
+        sender.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.stats().high_water, 1);
+    }
+
+    #[tokio::test]
+    async fn recv_ends_after_last_sender_drops() {
+        let (tx, mut rx) = unbounded_channel();
+        tx.try_send(1).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn send_fails_after_receiver_drops() {
+        let (tx, rx) = channel::<u32>(ChannelConfig::bounded(1, OverflowPolicy::Block));
+        drop(rx);
+        assert!(matches!(tx.send(1).await, Err(SendError::Closed(1))));
+        assert!(tx.is_closed());
+    }
+}
@@ -1,12 +1,77 @@
 use serde::{Deserialize, Serialize};
 
+use crate::protocol::{PROTOCOL_VERSION, Ping, Pong, SUPPORTED_FEATURES};
+
 crate::message_definitions! {
     pub enum FromClient {
         opcode => ClientOpcode;
         Handshake(Handshake) = 0x8000;
+        Login(Login) = 0x8001;
+        Register(Register) = 0x8002;
+        MoveInput(MoveInput) = 0x8003;
+        SnapshotAck(SnapshotAck) = 0x8004;
+        Ping(Ping) = 0x8005;
+        Pong(Pong) = 0x8006;
     }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Handshake {
+    pub protocol_version: u8,
+    pub build: Option<String>,
+    pub features: u32,
+}
+
+impl Handshake {
+    /// Handshake describing the running build.
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build: crate::build::get_version(),
+            features: SUPPORTED_FEATURES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Login {
+    pub account: String,
+    pub password: String,
+}
+
+/// Creates an account and logs into it.
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Register {
+    pub account: String,
+    pub password: String,
+}
+
+// Messages are logged with `{:?}`, so keep passwords out of it.
+impl std::fmt::Debug for Login {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Login").field("account", &self.account).finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Register").field("account", &self.account).finish_non_exhaustive()
+    }
+}
+
+/// Direction the player is holding, from `timestamp_ms` on the client's clock until the next
+/// input. The server moves the player itself, clients never send positions.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct MoveInput {
+    /// Increases with every input so the server can drop stale ones and acknowledge the latest.
+    pub sequence: u32,
+    pub dx: f32,
+    pub dy: f32,
+    pub timestamp_ms: u64,
+}
+
+/// Confirms a `Snapshot` arrived, making it the baseline of later ones.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct SnapshotAck {
+    pub tick: u32,
 }
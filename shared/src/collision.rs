@@ -1,11 +1,96 @@
-use std::{collections::{HashMap, HashSet}, hash::Hash};
+use std::{collections::{HashMap, HashSet}, hash::Hash, mem};
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 struct Cell(pub (i32, i32));
 
+/// Inclusive range of cells an item occupies.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Bounds {
+    min: Cell,
+    max: Cell,
+}
+
+impl Bounds {
+    fn contains(&self, cell: Cell) -> bool {
+        (self.min.0.0..=self.max.0.0).contains(&cell.0.0)
+            && (self.min.0.1..=self.max.0.1).contains(&cell.0.1)
+    }
+
+    fn cells(self) -> impl Iterator<Item = Cell> {
+        (self.min.0.0..=self.max.0.0)
+            .flat_map(move |x| (self.min.0.1..=self.max.0.1).map(move |y| Cell((x, y))))
+    }
+
+    fn union(self, other: Bounds) -> Bounds {
+        Bounds {
+            min: Cell((self.min.0.0.min(other.min.0.0), self.min.0.1.min(other.min.0.1))),
+            max: Cell((self.max.0.0.max(other.max.0.0), self.max.0.1.max(other.max.0.1))),
+        }
+    }
+}
+
+/// Exact box of an item, used by the precise queries once the cells narrowed things down.
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Rect {
+    min: (f32, f32),
+    max: (f32, f32),
+}
+
+impl Rect {
+    fn distance_to(&self, x: f32, y: f32) -> f32 {
+        let dx = (self.min.0 - x).max(0.0).max(x - self.max.0);
+        let dy = (self.min.1 - y).max(0.0).max(y - self.max.1);
+        dx.hypot(dy)
+    }
+
+    /// Distance along the ray at which it enters the box, 0 if it starts inside.
+    fn ray_entry(&self, origin: (f32, f32), inverse_direction: (f32, f32)) -> Option<f32> {
+        let (tx1, tx2) = (
+            (self.min.0 - origin.0) * inverse_direction.0,
+            (self.max.0 - origin.0) * inverse_direction.0,
+        );
+        let (ty1, ty2) = (
+            (self.min.1 - origin.1) * inverse_direction.1,
+            (self.max.1 - origin.1) * inverse_direction.1,
+        );
+        let entry = tx1.min(tx2).max(ty1.min(ty2)).max(0.0);
+        let exit = tx1.max(tx2).min(ty1.max(ty2));
+        // NaN comes from a zero direction component with the origin on the slab's edge.
+        (entry <= exit).then_some(entry)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    bounds: Bounds,
+    rect: Rect,
+}
+
+/// Closest item hit by a ray, and how far along the ray it was hit.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RayHit<T> {
+    pub item: T,
+    pub distance: f32,
+}
+
+/// Uniform spatial hash. Items are boxes centered on (`x`, `y`), bucketed into every cell they
+/// overlap.
+///
+/// Items can be moved in place with `update`, which only touches the cells they enter or leave.
+/// When many items move in a tick, `mark_dirty` records the new positions and `flush` re-buckets
+/// them in one go; queries keep seeing the old positions until then.
+///
+/// Queries come in three flavours: returning a fresh collection, filling a caller's buffer
+/// (`*_into`), or calling a visitor for each item (`*_visit`). The last two never allocate, and
+/// every flavour reports each item once.
 pub struct Grid<T: Copy + Eq + Hash> {
     cell_size: f32,
     cells: HashMap<Cell, Vec<T>>,
+    entries: HashMap<T, Entry>,
+    dirty: HashMap<T, Entry>,
+    /// Every cell that was ever occupied lies within this, bounding raycasts and
+    /// nearest-neighbour searches.
+    extent: Option<Bounds>,
 }
 
 impl<T: Copy + Eq + Hash> Grid<T> {
@@ -13,6 +98,9 @@ impl<T: Copy + Eq + Hash> Grid<T> {
         Self {
             cell_size,
             cells: HashMap::default(),
+            entries: HashMap::default(),
+            dirty: HashMap::default(),
+            extent: None,
         }
     }
 
@@ -20,33 +108,392 @@ impl<T: Copy + Eq + Hash> Grid<T> {
         Cell(((x / self.cell_size).floor() as i32, (y / self.cell_size).floor() as i32))
     }
 
+    fn bounds_of(&self, x: f32, y: f32, w: f32, h: f32) -> Bounds {
+        let min = self.cell_of(x - w / 2.0, y - h / 2.0);
+        let max = self.cell_of(x + w / 2.0 - f32::EPSILON, y + h / 2.0 - f32::EPSILON);
+        // Points on a cell edge would otherwise end before they start and occupy no cell.
+        Bounds {
+            min,
+            max: Cell((max.0.0.max(min.0.0), max.0.1.max(min.0.1))),
+        }
+    }
+
+    fn entry_of(&self, x: f32, y: f32, w: f32, h: f32) -> Entry {
+        Entry {
+            bounds: self.bounds_of(x, y, w, h),
+            rect: Rect {
+                min: (x - w / 2.0, y - h / 2.0),
+                max: (x + w / 2.0, y + h / 2.0),
+            },
+        }
+    }
+
     pub fn clear(&mut self) {
         self.cells.clear();
+        self.entries.clear();
+        self.dirty.clear();
+        self.extent = None;
     }
 
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, t: &T) -> bool {
+        self.entries.contains_key(t)
+    }
+
+    /// Adds `t`, or moves it if it is already in the grid.
     pub fn insert(&mut self, t: T, x: f32, y: f32, w: f32, h: f32) {
-        let min = self.cell_of(x - w / 2.0, y - h / 2.0);
-        let max = self.cell_of(x + w / 2.0 - f32::EPSILON, y + h / 2.0 - f32::EPSILON);
-        for x in min.0.0..=max.0.0 {
-            for y in min.0.1..=max.0.1 {
-                self.cells.entry(Cell((x, y))).or_default().push(t);
+        self.update(t, x, y, w, h);
+    }
+
+    /// Moves `t` to its new box, inserting it if needed. Returns whether any cell changed.
+    pub fn update(&mut self, t: T, x: f32, y: f32, w: f32, h: f32) -> bool {
+        self.dirty.remove(&t);
+        let entry = self.entry_of(x, y, w, h);
+        self.move_to(t, entry)
+    }
+
+    pub fn remove(&mut self, t: &T) -> bool {
+        self.dirty.remove(t);
+        let Some(entry) = self.entries.remove(t) else {
+            return false;
+        };
+        for cell in entry.bounds.cells() {
+            self.remove_from_cell(cell, t);
+        }
+        true
+    }
+
+    /// Records a new box for `t`, applied on the next `flush`. Marking an item twice keeps the
+    /// latest box.
+    pub fn mark_dirty(&mut self, t: T, x: f32, y: f32, w: f32, h: f32) {
+        let entry = self.entry_of(x, y, w, h);
+        self.dirty.insert(t, entry);
+    }
+
+    /// Applies every pending `mark_dirty`. Returns how many items changed cells.
+    pub fn flush(&mut self) -> usize {
+        let dirty = mem::take(&mut self.dirty);
+        let mut moved = 0;
+        for (t, entry) in dirty {
+            if self.move_to(t, entry) {
+                moved += 1;
             }
         }
+        moved
+    }
+
+    fn move_to(&mut self, t: T, entry: Entry) -> bool {
+        let bounds = entry.bounds;
+        let old = self.entries.insert(t, entry).map(|old| old.bounds);
+        if old == Some(bounds) {
+            return false;
+        }
+        if let Some(old) = old {
+            for cell in old.cells().filter(|cell| !bounds.contains(*cell)) {
+                self.remove_from_cell(cell, &t);
+            }
+        }
+        for cell in bounds.cells() {
+            if old.is_none_or(|old| !old.contains(cell)) {
+                self.cells.entry(cell).or_default().push(t);
+            }
+        }
+        self.extent = Some(self.extent.map_or(bounds, |extent| extent.union(bounds)));
+        true
+    }
+
+    fn remove_from_cell(&mut self, cell: Cell, t: &T) {
+        let Some(list) = self.cells.get_mut(&cell) else {
+            return;
+        };
+        if let Some(index) = list.iter().position(|item| item == t) {
+            list.swap_remove(index);
+        }
+        if list.is_empty() {
+            self.cells.remove(&cell);
+        }
     }
 
+    /// Items whose cells overlap the box. Coarse: items near the box may be included.
     pub fn query(&self, x: f32, y: f32, w: f32, h: f32) -> HashSet<T> {
         let mut result = HashSet::default();
-        let min = self.cell_of(x - w / 2.0, y - h / 2.0);
-        let max = self.cell_of(x + w / 2.0 - f32::EPSILON, y + h / 2.0 - f32::EPSILON);
-        for x in min.0.0..=max.0.0 {
-            for y in min.0.1..=max.0.1 {
-                if let Some(list) = self.cells.get(&Cell((x, y))) {
-                    result.extend(list.iter().copied());
+        self.query_visit(x, y, w, h, |t| {
+            result.insert(t);
+        });
+        result
+    }
+
+    pub fn query_into(&self, x: f32, y: f32, w: f32, h: f32, out: &mut Vec<T>) {
+        out.clear();
+        self.query_visit(x, y, w, h, |t| out.push(t));
+    }
+
+    pub fn query_visit(&self, x: f32, y: f32, w: f32, h: f32, mut visit: impl FnMut(T)) {
+        let query = self.bounds_of(x, y, w, h);
+        for cell in query.cells() {
+            let Some(list) = self.cells.get(&cell) else {
+                continue;
+            };
+            for t in list {
+                // An item spanning several cells is only reported from the first one the query
+                // shares with it.
+                let bounds = self.entries[t].bounds;
+                let first = Cell((
+                    bounds.min.0.0.max(query.min.0.0),
+                    bounds.min.0.1.max(query.min.0.1),
+                ));
+                if first == cell {
+                    visit(*t);
                 }
             }
         }
+    }
+
+    /// Items whose box is within `radius` of (`x`, `y`).
+    pub fn query_radius(&self, x: f32, y: f32, radius: f32) -> HashSet<T> {
+        let mut result = HashSet::default();
+        self.query_radius_visit(x, y, radius, |t| {
+            result.insert(t);
+        });
         result
     }
+
+    pub fn query_radius_into(&self, x: f32, y: f32, radius: f32, out: &mut Vec<T>) {
+        out.clear();
+        self.query_radius_visit(x, y, radius, |t| out.push(t));
+    }
+
+    pub fn query_radius_visit(&self, x: f32, y: f32, radius: f32, mut visit: impl FnMut(T)) {
+        let size = radius * 2.0;
+        self.query_visit(x, y, size, size, |t| {
+            if self.entries[&t].rect.distance_to(x, y) <= radius {
+                visit(t);
+            }
+        });
+    }
+
+    /// Closest item hit by a ray from `origin` along `direction`, up to `max_distance`. Items
+    /// `filter` rejects, like the shooter itself, are passed through.
+    pub fn raycast(
+        &self,
+        origin: (f32, f32),
+        direction: (f32, f32),
+        max_distance: f32,
+        mut filter: impl FnMut(T) -> bool,
+    ) -> Option<RayHit<T>> {
+        let mut closest: Option<RayHit<T>> = None;
+        self.walk_ray(origin, direction, max_distance, |item, distance| {
+            if filter(item) {
+                closest = Some(RayHit { item, distance });
+                // Only closer hits are interesting from now on.
+                return Some(distance);
+            }
+            Some(max_distance.min(closest.map_or(f32::INFINITY, |closest| closest.distance)))
+        });
+        closest
+    }
+
+    /// Closest item crossed when moving from `from` to `to`, as with `raycast`.
+    pub fn segment_cast(
+        &self,
+        from: (f32, f32),
+        to: (f32, f32),
+        filter: impl FnMut(T) -> bool,
+    ) -> Option<RayHit<T>> {
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let length = dx.hypot(dy);
+        if length == 0.0 {
+            return None;
+        }
+        self.raycast(from, (dx / length, dy / length), length, filter)
+    }
+
+    /// Calls `visit(item, distance)` for every item the ray hits within `max_distance`. Hits
+    /// come cell by cell along the ray, so they are only roughly sorted. Return `false` to stop.
+    pub fn raycast_visit(
+        &self,
+        origin: (f32, f32),
+        direction: (f32, f32),
+        max_distance: f32,
+        mut visit: impl FnMut(T, f32) -> bool,
+    ) {
+        self.walk_ray(origin, direction, max_distance, |item, distance| {
+            visit(item, distance).then_some(max_distance)
+        });
+    }
+
+    /// Walks the cells along the ray, calling `visit` once for each item hit closer than the
+    /// current limit. `visit` returns the new limit, or `None` to stop.
+    fn walk_ray(
+        &self,
+        origin: (f32, f32),
+        direction: (f32, f32),
+        max_distance: f32,
+        mut visit: impl FnMut(T, f32) -> Option<f32>,
+    ) {
+        let length = direction.0.hypot(direction.1);
+        let Some(extent) = self.extent else {
+            return;
+        };
+        // No cell would ever be left, with an unbounded `max_distance` the walk would not end.
+        if length == 0.0 || !length.is_finite() || !origin.0.is_finite() || !origin.1.is_finite() {
+            return;
+        }
+        let direction = (direction.0 / length, direction.1 / length);
+        let inverse = (1.0 / direction.0, 1.0 / direction.1);
+        let step = |d: f32| if d > 0.0 { 1 } else if d < 0.0 { -1 } else { 0 };
+        let step = (step(direction.0), step(direction.1));
+        // Distance along the ray to the next cell boundary on one axis.
+        let first_boundary = |cell: i32, step: i32, origin: f32, inverse: f32| match step {
+            0 => f32::INFINITY,
+            1 => ((cell + 1) as f32 * self.cell_size - origin) * inverse,
+            _ => (cell as f32 * self.cell_size - origin) * inverse,
+        };
+        let mut cell = self.cell_of(origin.0, origin.1);
+        let mut next = (
+            first_boundary(cell.0.0, step.0, origin.0, inverse.0),
+            first_boundary(cell.0.1, step.1, origin.1, inverse.1),
+        );
+        let delta = (self.cell_size * inverse.0.abs(), self.cell_size * inverse.1.abs());
+        let mut limit = max_distance;
+        let mut previous: Option<Cell> = None;
+        let mut cell_entry = 0.0;
+        while cell_entry <= limit {
+            if let Some(list) = self.cells.get(&cell) {
+                for t in list {
+                    let entry = self.entries[t];
+                    // The ray crosses an item's cells in one run; report it from the first.
+                    if previous.is_some_and(|previous| entry.bounds.contains(previous)) {
+                        continue;
+                    }
+                    let Some(distance) = entry.rect.ray_entry(origin, inverse) else {
+                        continue;
+                    };
+                    if distance > limit {
+                        continue;
+                    }
+                    match visit(*t, distance) {
+                        Some(new_limit) => limit = new_limit,
+                        None => return,
+                    }
+                }
+            }
+            if !Self::moving_towards(cell, step, extent) {
+                return;
+            }
+            previous = Some(cell);
+            if next.0 < next.1 {
+                cell_entry = next.0;
+                cell.0.0 += step.0;
+                next.0 += delta.0;
+            } else {
+                cell_entry = next.1;
+                cell.0.1 += step.1;
+                next.1 += delta.1;
+            }
+        }
+    }
+
+    /// Whether stepping further can still reach an occupied cell. A ray on the extent's last
+    /// column can still step along the other axis into occupied cells, so only rays already past
+    /// it stop.
+    fn moving_towards(cell: Cell, step: (i32, i32), extent: Bounds) -> bool {
+        let x_ok = match step.0 {
+            1 => cell.0.0 <= extent.max.0.0,
+            -1 => cell.0.0 >= extent.min.0.0,
+            _ => extent.min.0.0 <= cell.0.0 && cell.0.0 <= extent.max.0.0,
+        };
+        let y_ok = match step.1 {
+            1 => cell.0.1 <= extent.max.0.1,
+            -1 => cell.0.1 >= extent.min.0.1,
+            _ => extent.min.0.1 <= cell.0.1 && cell.0.1 <= extent.max.0.1,
+        };
+        x_ok && y_ok
+    }
+
+    /// The `k` items closest to (`x`, `y`), nearest first, with their distance. Items containing
+    /// the point are at distance 0.
+    pub fn nearest(&self, x: f32, y: f32, k: usize) -> Vec<(T, f32)> {
+        let mut out = Vec::with_capacity(k);
+        self.nearest_into(x, y, k, &mut out);
+        out
+    }
+
+    /// As `nearest`, reusing `out`. Only allocates if `out` has less than `k` capacity.
+    pub fn nearest_into(&self, x: f32, y: f32, k: usize, out: &mut Vec<(T, f32)>) {
+        out.clear();
+        let Some(extent) = self.extent else {
+            return;
+        };
+        if k == 0 {
+            return;
+        }
+        let center = self.cell_of(x, y);
+        // Rings closer in than the extent are empty.
+        let min_ring = [
+            extent.min.0.0 - center.0.0,
+            center.0.0 - extent.max.0.0,
+            extent.min.0.1 - center.0.1,
+            center.0.1 - extent.max.0.1,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+        .max(0);
+        let max_ring = [
+            center.0.0 - extent.min.0.0,
+            extent.max.0.0 - center.0.0,
+            center.0.1 - extent.min.0.1,
+            extent.max.0.1 - center.0.1,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+        .max(0);
+        for ring in min_ring..=max_ring {
+            // Anything in this ring or further out is at least this far away.
+            let ring_distance = (ring - 1).max(0) as f32 * self.cell_size;
+            if out.len() == k && out[k - 1].1 < ring_distance {
+                break;
+            }
+            for cell in Self::ring(center, ring) {
+                let Some(list) = self.cells.get(&cell) else {
+                    continue;
+                };
+                for t in list {
+                    if out.iter().any(|(item, _)| item == t) {
+                        continue;
+                    }
+                    let distance = self.entries[t].rect.distance_to(x, y);
+                    if out.len() == k && distance >= out[k - 1].1 {
+                        continue;
+                    }
+                    let index = out.partition_point(|(_, other)| *other <= distance);
+                    if out.len() == k {
+                        out.pop();
+                    }
+                    out.insert(index, (*t, distance));
+                }
+            }
+        }
+    }
+
+    /// Cells at Chebyshev distance `ring` from `center`.
+    fn ring(center: Cell, ring: i32) -> impl Iterator<Item = Cell> {
+        let (cx, cy) = center.0;
+        (cx - ring..=cx + ring).flat_map(move |x| {
+            (cy - ring..=cy + ring)
+                .filter(move |y| ring == 0 || x == cx - ring || x == cx + ring || *y == cy - ring || *y == cy + ring)
+                .map(move |y| Cell((x, y)))
+        })
+    }
 }
 
 #[cfg(test)]
@@ -63,6 +510,14 @@ mod test {
         }
     }
 
+    #[test]
+    fn point_items_occupy_their_cell() {
+        let mut grid: Grid<u32> = Grid::new(1.0);
+        grid.insert(1, 0.0, 0.0, 0.0, 0.0);
+        assert_eq!(grid.cells.get(&Cell((0, 0))).unwrap().as_slice(), &[1]);
+        assert_eq!(grid.query_radius(0.5, 0.0, 1.0), HashSet::from([1]));
+    }
+
     #[test]
     fn insert_negative_coords_floor_correctly() {
         let mut grid: Grid<u32> = Grid::new(1.0);
@@ -81,4 +536,139 @@ mod test {
         assert!(hits.contains(&2));
         assert_eq!(hits.len(), 2);
     }
+
+    #[test]
+    fn update_moves_between_cells() {
+        let mut grid: Grid<u32> = Grid::new(1.0);
+        grid.insert(1, 0.5, 0.5, 0.5, 0.5);
+        assert!(!grid.update(1, 0.6, 0.6, 0.5, 0.5));
+        assert!(grid.update(1, 1.0, 0.5, 0.5, 0.5));
+        assert_eq!(grid.cells.len(), 2);
+        assert!(grid.update(1, 5.5, 5.5, 0.5, 0.5));
+        assert_eq!(grid.cells.len(), 1);
+        assert_eq!(grid.cells.get(&Cell((5, 5))).unwrap().as_slice(), &[1]);
+        assert!(grid.query(0.5, 0.5, 1.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn remove_empties_cells() {
+        let mut grid: Grid<u32> = Grid::new(1.0);
+        grid.insert(1, 0.0, 0.0, 2.0, 2.0);
+        grid.insert(2, 0.5, 0.5, 0.5, 0.5);
+        assert!(grid.remove(&1));
+        assert!(!grid.remove(&1));
+        assert_eq!(grid.len(), 1);
+        assert_eq!(grid.cells.len(), 1);
+    }
+
+    #[test]
+    fn dirty_items_move_on_flush() {
+        let mut grid: Grid<u32> = Grid::new(1.0);
+        grid.insert(1, 0.5, 0.5, 0.5, 0.5);
+        grid.insert(2, 0.5, 0.5, 0.5, 0.5);
+        grid.mark_dirty(1, 3.5, 3.5, 0.5, 0.5);
+        grid.mark_dirty(2, 0.6, 0.6, 0.5, 0.5);
+        assert!(grid.query(3.5, 3.5, 0.5, 0.5).is_empty());
+        assert_eq!(grid.flush(), 1);
+        assert_eq!(grid.query(3.5, 3.5, 0.5, 0.5), HashSet::from([1]));
+        assert_eq!(grid.query(0.5, 0.5, 0.5, 0.5), HashSet::from([2]));
+    }
+
+    fn scattered() -> Grid<u32> {
+        let mut grid: Grid<u32> = Grid::new(4.0);
+        grid.insert(1, 10.0, 0.0, 2.0, 2.0);
+        grid.insert(2, 20.0, 0.0, 2.0, 2.0);
+        grid.insert(3, 0.0, 10.0, 2.0, 2.0);
+        grid.insert(4, -30.0, -30.0, 20.0, 20.0);
+        grid
+    }
+
+    #[test]
+    fn visitors_report_spanning_items_once() {
+        let grid = scattered();
+        let mut out = Vec::new();
+        grid.query_into(-30.0, -30.0, 40.0, 40.0, &mut out);
+        assert_eq!(out, vec![4]);
+        let mut visits = 0;
+        grid.query_visit(0.0, 0.0, 100.0, 100.0, |_| visits += 1);
+        assert_eq!(visits, 4);
+    }
+
+    #[test]
+    fn radius_query_checks_distance_to_boxes() {
+        let grid = scattered();
+        assert_eq!(grid.query_radius(0.0, 0.0, 9.5), HashSet::from([1, 3]));
+        assert_eq!(grid.query_radius(0.0, 0.0, 8.0), HashSet::new());
+        assert!(grid.query_radius(0.0, 0.0, 29.0).contains(&4));
+    }
+
+    #[test]
+    fn raycast_finds_closest_hit() {
+        let grid = scattered();
+        let hit = grid.raycast((0.0, 0.0), (1.0, 0.0), 100.0, |_| true).unwrap();
+        assert_eq!(hit, RayHit { item: 1, distance: 9.0 });
+        let hit = grid.raycast((0.0, 0.0), (1.0, 0.0), 100.0, |item| item != 1).unwrap();
+        assert_eq!(hit, RayHit { item: 2, distance: 19.0 });
+        assert_eq!(grid.raycast((0.0, 0.0), (1.0, 0.0), 5.0, |_| true), None);
+        assert_eq!(grid.raycast((30.0, 0.0), (-1.0, 0.0), 100.0, |_| true).unwrap().item, 2);
+        let hit = grid.segment_cast((0.0, 0.0), (-30.0, -30.0), |_| true).unwrap();
+        assert_eq!(hit.item, 4);
+        assert!((hit.distance - 20.0 * 2.0_f32.sqrt()).abs() < 1e-3);
+        assert_eq!(grid.segment_cast((0.0, 0.0), (0.0, 5.0), |_| true), None);
+
+        let mut hits = Vec::new();
+        grid.raycast_visit((0.0, 0.0), (1.0, 0.0), 100.0, |item, _| {
+            hits.push(item);
+            true
+        });
+        assert_eq!(hits, vec![1, 2]);
+    }
+
+    #[test]
+    fn raycast_reaches_the_edge_of_the_extent() {
+        let mut grid = Grid::new(1.0);
+        grid.insert(1, 0.5, 0.5, 0.2, 0.2);
+        grid.insert(2, 2.4, 1.5, 0.8, 0.8);
+        // The ray reaches the extent's last column before climbing into the second item's row.
+        let hit = grid.raycast((0.5, 0.5), (1.0, 0.3), 100.0, |item| item != 1).unwrap();
+        assert_eq!(hit.item, 2);
+    }
+
+    /// One item on the ray from `(0.5, 0.0)` along x, and rays unbounded in length.
+    fn unbounded_raycast(origin: (f32, f32), direction: (f32, f32)) -> Option<RayHit<u32>> {
+        let mut grid = Grid::new(1.0);
+        grid.insert(1, 2.0, 0.0, 1.0, 1.0);
+        grid.raycast(origin, direction, f32::INFINITY, |_| true)
+    }
+
+    #[test]
+    fn raycast_ignores_zero_length_directions() {
+        assert!(unbounded_raycast((0.5, 0.0), (1.0, 0.0)).is_some());
+        assert!(unbounded_raycast((0.5, 0.0), (0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn raycast_ignores_non_finite_directions() {
+        assert!(unbounded_raycast((0.5, 0.0), (f32::NAN, 0.0)).is_none());
+        assert!(unbounded_raycast((0.5, 0.0), (f32::INFINITY, 0.0)).is_none());
+        assert!(unbounded_raycast((0.5, 0.0), (f32::MAX, f32::MAX)).is_none());
+    }
+
+    #[test]
+    fn raycast_ignores_non_finite_origins() {
+        assert!(unbounded_raycast((f32::NAN, 0.0), (1.0, 0.0)).is_none());
+        assert!(unbounded_raycast((f32::NEG_INFINITY, 0.0), (1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn nearest_returns_k_closest_in_order() {
+        let grid = scattered();
+        let nearest: Vec<u32> = grid.nearest(9.0, 1.0, 3).into_iter().map(|(item, _)| item).collect();
+        assert_eq!(nearest, vec![1, 2, 3]);
+        let mut out = Vec::new();
+        grid.nearest_into(-100.0, -100.0, 1, &mut out);
+        assert_eq!(out[0].0, 4);
+        assert_eq!(grid.nearest(0.0, 0.0, 10).len(), 4);
+        assert!(Grid::<u32>::new(1.0).nearest(0.0, 0.0, 3).is_empty());
+    }
 }
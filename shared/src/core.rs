@@ -1,15 +1,19 @@
+use std::{fmt, time::Duration};
+
 use tokio::task::JoinHandle;
-use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+
+use crate::channel::{QueueStats, Receiver, Sender};
 
 pub struct Core<Command, Event = ()> {
-    pub tx: tokio::sync::mpsc::UnboundedSender<Command>,
-    pub rx: Option<tokio::sync::mpsc::UnboundedReceiver<Event>>,
+    pub tx: Sender<Command>,
+    pub rx: Option<Receiver<Event>>,
     pub handle: tokio::task::JoinHandle<()>,
 }
 
 impl<Command, Event> Core<Command, Event> {
     pub fn new(
-        tx: mpsc::UnboundedSender<Command>,
+        tx: Sender<Command>,
         handle: JoinHandle<()>,
     ) -> Self {
         Self {
@@ -19,24 +23,148 @@ impl<Command, Event> Core<Command, Event> {
         }
     }
 
-    pub fn with_events(mut self, rx: tokio::sync::mpsc::UnboundedReceiver<Event>) -> Self {
+    pub fn with_events(mut self, rx: Receiver<Event>) -> Self {
         self.rx = Some(rx);
         self
     }
 
-    pub fn into_parts(self) -> (JoinHandle<()>, mpsc::UnboundedSender<Command>, Option<mpsc::UnboundedReceiver<Event>>) {
+    pub fn into_parts(self) -> (JoinHandle<()>, Sender<Command>, Option<Receiver<Event>>) {
         (self.handle, self.tx, self.rx)
     }
 
-    pub fn take_rx(&mut self) -> Option<mpsc::UnboundedReceiver<Event>> {
+    pub fn take_rx(&mut self) -> Option<Receiver<Event>> {
         self.rx.take()
     }
 
+    /// Depth and overflow counters of the core's command queue.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.tx.stats()
+    }
+
+    /// Sends the command built by `build` and waits up to `timeout` for the core to answer
+    /// through the supplied `Reply`.
+    pub async fn request<T>(
+        &self,
+        build: impl FnOnce(Reply<T>) -> Command,
+        timeout: Duration,
+    ) -> Result<T, RequestError> {
+        request(&self.tx, build, timeout).await
+    }
+
     pub async fn stop(self, stop_command: Option<Command>) -> Result<(), tokio::task::JoinError> {
         if let Some(command) = stop_command {
-            let _ = self.tx.send(command);
+            let _ = self.tx.send(command).await;
         }
         self.handle.await?;
         Ok(())
     }
 }
+
+/// Answer half of a request, carried inside the command it belongs to.
+pub struct Reply<T>(oneshot::Sender<T>);
+
+impl<T> Reply<T> {
+    /// Answers the request. If the caller already gave up waiting the value is dropped.
+    pub fn send(self, value: T) {
+        let _ = self.0.send(value);
+    }
+}
+
+impl<T> fmt::Debug for Reply<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Reply")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// The core's command channel is closed.
+    Closed,
+    /// The core dropped the reply without answering.
+    Unanswered,
+    Timeout,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "command channel closed"),
+            Self::Unanswered => write!(f, "request was dropped without a reply"),
+            Self::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Same as `Core::request`, for callers that only hold the command sender of a core.
+pub async fn request<Command, T>(
+    tx: &Sender<Command>,
+    build: impl FnOnce(Reply<T>) -> Command,
+    timeout: Duration,
+) -> Result<T, RequestError> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    // The deadline covers waiting for room in a full queue as well as the answer itself.
+    tokio::time::timeout(timeout, async move {
+        tx.send(build(Reply(reply_tx)))
+            .await
+            .map_err(|_| RequestError::Closed)?;
+        reply_rx.await.map_err(|_| RequestError::Unanswered)
+    })
+    .await
+    .unwrap_or(Err(RequestError::Timeout))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::unbounded_channel;
+
+    enum Command {
+        Double { value: u32, reply: Reply<u32> },
+        Ignore { _reply: Reply<u32> },
+        Hold { reply: Reply<u32> },
+    }
+
+    fn spawn_core() -> Core<Command> {
+        let (tx, mut rx) = unbounded_channel();
+        let handle = tokio::spawn(async move {
+            let mut held = vec![];
+            while let Some(command) = rx.recv().await {
+                match command {
+                    Command::Double { value, reply } => reply.send(value * 2),
+                    Command::Ignore { .. } => {}
+                    Command::Hold { reply } => held.push(reply),
+                }
+            }
+        });
+        Core::new(tx, handle)
+    }
+
+    #[tokio::test]
+    async fn request_returns_reply() {
+        let core = spawn_core();
+        let value = core
+            .request(|reply| Command::Double { value: 21, reply }, Duration::from_secs(1))
+            .await;
+        assert_eq!(value, Ok(42));
+    }
+
+    #[tokio::test]
+    async fn request_reports_dropped_reply() {
+        let core = spawn_core();
+        let value = core
+            .request(|_reply| Command::Ignore { _reply }, Duration::from_secs(1))
+            .await;
+        assert_eq!(value, Err(RequestError::Unanswered));
+    }
+
+    #[tokio::test]
+    async fn request_times_out() {
+        let core = spawn_core();
+        let value = core
+            .request(|reply| Command::Hold { reply }, Duration::from_millis(10))
+            .await;
+        assert_eq!(value, Err(RequestError::Timeout));
+    }
+}
@@ -1,7 +1,9 @@
 use bytes::{Buf, BufMut, BytesMut};
-use std::{io, marker::PhantomData};
+use std::{fmt, io, marker::PhantomData};
 use tokio_util::codec::{Decoder, Encoder};
 
+use crate::protocol::PROTOCOL_VERSION;
+
 pub trait Opcode: Copy + Sized {
     fn from_raw(raw: u16) -> Result<Self, u16>;
     fn into_raw(self) -> u16;
@@ -18,7 +20,7 @@ pub struct MessageFrame<O: Opcode> {
 impl<O: Opcode> MessageFrame<O> {
     pub fn new(opcode: O, payload: Vec<u8>) -> Self {
         Self {
-            version: 0,
+            version: PROTOCOL_VERSION,
             flags: 0,
             opcode,
             payload,
@@ -26,24 +28,126 @@ impl<O: Opcode> MessageFrame<O> {
     }
 }
 
+/// Size of the fixed frame header: version, flags, opcode and payload length.
+pub const HEADER_LEN: usize = 8;
+
+/// Default upper bound for a single frame payload.
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 1024 * 1024;
+
+/// Default payload size from which `MsgCodec` starts compressing outbound frames.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+/// Header flag marking the payload as LZ4 compressed, prefixed with its uncompressed size.
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    PayloadTooLarge {
+        len: usize,
+        max: usize,
+    },
+    DecompressedTooLarge {
+        len: usize,
+        max: usize,
+    },
+    UnknownOpcode {
+        opcode: u16,
+        version: u8,
+    },
+    Decompress(lz4_flex::block::DecompressError),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::PayloadTooLarge { len, max } => {
+                write!(f, "payload of {len} bytes exceeds the maximum payload size of {max} bytes")
+            }
+            Self::DecompressedTooLarge { len, max } => write!(
+                f,
+                "decompressed payload of {len} bytes exceeds the maximum payload size of {max} bytes"
+            ),
+            Self::Decompress(err) => write!(f, "failed to decompress payload: {err}"),
+            // A peer speaking another protocol version will mostly send opcodes we do not know,
+            // so report the version mismatch instead of the opcode it tripped over.
+            Self::UnknownOpcode { opcode, version } if *version != PROTOCOL_VERSION => write!(
+                f,
+                "unknown opcode {opcode:#06x} from protocol version {version} (expected {PROTOCOL_VERSION})"
+            ),
+            Self::UnknownOpcode { opcode, .. } => write!(f, "unknown opcode {opcode:#06x}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
 pub struct MsgCodec<O: Opcode> {
+    max_payload_len: usize,
+    compression_threshold: Option<usize>,
     _marker: PhantomData<O>,
 }
 
-impl<O: Opcode> Default for MsgCodec<O> {
-    fn default() -> Self {
+impl<O: Opcode> MsgCodec<O> {
+    pub fn new(max_payload_len: usize) -> Self {
         Self {
+            max_payload_len,
+            compression_threshold: None,
             _marker: PhantomData,
         }
     }
+
+    /// Compresses outbound payloads of at least `threshold` bytes. Inbound compressed frames are
+    /// always accepted.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    pub fn max_payload_len(&self) -> usize {
+        self.max_payload_len
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+        // Check the announced size ourselves so a small frame cannot expand past the limit.
+        let Some((size, compressed)) = payload.split_first_chunk::<4>() else {
+            return Err(FrameError::Decompress(
+                lz4_flex::block::DecompressError::ExpectedAnotherByte,
+            ));
+        };
+        let len = u32::from_le_bytes(*size) as usize;
+        if len > self.max_payload_len {
+            return Err(FrameError::DecompressedTooLarge {
+                len,
+                max: self.max_payload_len,
+            });
+        }
+        lz4_flex::block::decompress(compressed, len).map_err(FrameError::Decompress)
+    }
+}
+
+impl<O: Opcode> Default for MsgCodec<O> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PAYLOAD_LEN)
+    }
 }
 
 impl<O: Opcode> Decoder for MsgCodec<O> {
     type Item = MessageFrame<O>;
-    type Error = io::Error;
+    type Error = FrameError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        const HEADER_LEN: usize = 8;
         if src.len() < HEADER_LEN {
             return Ok(None);
         }
@@ -53,20 +157,31 @@ impl<O: Opcode> Decoder for MsgCodec<O> {
         let opcode_raw = buf.get_u16_le();
         let len = buf.get_u32_le() as usize;
 
+        // Validate the header before buffering anything, so a bogus length cannot make us
+        // allocate for a payload that will be rejected anyway.
+        if len > self.max_payload_len {
+            return Err(FrameError::PayloadTooLarge {
+                len,
+                max: self.max_payload_len,
+            });
+        }
+        let opcode = O::from_raw(opcode_raw).map_err(|opcode| FrameError::UnknownOpcode {
+            opcode,
+            version,
+        })?;
+
         if src.len() < HEADER_LEN + len {
             src.reserve(HEADER_LEN + len - src.len());
             return Ok(None);
         }
 
         src.advance(HEADER_LEN);
-        let payload = src.split_to(len).to_vec();
-
-        let opcode = O::from_raw(opcode_raw).map_err(|raw| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("unknown opcode {raw:#06x}"),
-            )
-        })?;
+        let mut payload = src.split_to(len).to_vec();
+        let mut flags = flags;
+        if flags & FLAG_COMPRESSED != 0 {
+            payload = self.decompress(&payload)?;
+            flags &= !FLAG_COMPRESSED;
+        }
 
         Ok(Some(MessageFrame {
             version,
@@ -78,10 +193,26 @@ impl<O: Opcode> Decoder for MsgCodec<O> {
 }
 
 impl<O: Opcode> Encoder<MessageFrame<O>> for MsgCodec<O> {
-    type Error = io::Error;
+    type Error = FrameError;
 
-    fn encode(&mut self, frame: MessageFrame<O>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        const HEADER_LEN: usize = 8;
+    fn encode(&mut self, mut frame: MessageFrame<O>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if frame.payload.len() > self.max_payload_len {
+            return Err(FrameError::PayloadTooLarge {
+                len: frame.payload.len(),
+                max: self.max_payload_len,
+            });
+        }
+        if let Some(threshold) = self.compression_threshold
+            && frame.flags & FLAG_COMPRESSED == 0
+            && frame.payload.len() >= threshold
+        {
+            let compressed = lz4_flex::block::compress_prepend_size(&frame.payload);
+            // Incompressible payloads go out as they are.
+            if compressed.len() < frame.payload.len() {
+                frame.payload = compressed;
+                frame.flags |= FLAG_COMPRESSED;
+            }
+        }
         dst.reserve(HEADER_LEN + frame.payload.len());
         dst.put_u8(frame.version);
         dst.put_u8(frame.flags);
@@ -91,3 +222,97 @@ impl<O: Opcode> Encoder<MessageFrame<O>> for MsgCodec<O> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client_messages::ClientOpcode;
+
+    fn encode(codec: &mut MsgCodec<ClientOpcode>, payload: Vec<u8>) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec
+            .encode(MessageFrame::new(ClientOpcode::Handshake, payload), &mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn decode_round_trips_encoded_frame() {
+        let mut codec = MsgCodec::<ClientOpcode>::default();
+        let mut buf = encode(&mut codec, vec![1, 2, 3]);
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.version, PROTOCOL_VERSION);
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_oversized_length_before_buffering() {
+        let mut codec = MsgCodec::<ClientOpcode>::new(16);
+        let mut buf = BytesMut::new();
+        buf.put_u8(PROTOCOL_VERSION);
+        buf.put_u8(0);
+        buf.put_u16_le(ClientOpcode::Handshake.into_raw());
+        buf.put_u32_le(u32::MAX);
+        let capacity = buf.capacity();
+        match codec.decode(&mut buf) {
+            Err(FrameError::PayloadTooLarge { len, max }) => {
+                assert_eq!(len, u32::MAX as usize);
+                assert_eq!(max, 16);
+            }
+            other => panic!("unexpected decode result: {other:?}"),
+        }
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    #[test]
+    fn decode_rejects_unknown_opcode() {
+        let mut codec = MsgCodec::<ClientOpcode>::default();
+        let mut buf = BytesMut::new();
+        buf.put_u8(PROTOCOL_VERSION + 1);
+        buf.put_u8(0);
+        buf.put_u16_le(0x1234);
+        buf.put_u32_le(0);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(err, FrameError::UnknownOpcode { opcode: 0x1234, .. }));
+        assert!(err.to_string().contains("protocol version"));
+    }
+
+    #[test]
+    fn compression_is_transparent_to_decode() {
+        let mut codec = MsgCodec::<ClientOpcode>::default().with_compression(16);
+        let payload = vec![7; 4096];
+        let mut buf = encode(&mut codec, payload.clone());
+        assert_eq!(buf[1] & FLAG_COMPRESSED, FLAG_COMPRESSED);
+        assert!(buf.len() < HEADER_LEN + payload.len());
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.flags & FLAG_COMPRESSED, 0);
+        assert_eq!(frame.payload, payload);
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let mut codec = MsgCodec::<ClientOpcode>::default().with_compression(16);
+        let buf = encode(&mut codec, vec![7; 8]);
+        assert_eq!(buf[1] & FLAG_COMPRESSED, 0);
+    }
+
+    #[test]
+    fn decode_rejects_decompression_past_limit() {
+        let mut encoder = MsgCodec::<ClientOpcode>::default().with_compression(16);
+        let mut buf = encode(&mut encoder, vec![0; 4096]);
+        let mut decoder = MsgCodec::<ClientOpcode>::new(1024);
+        let err = decoder.decode(&mut buf).unwrap_err();
+        assert!(matches!(err, FrameError::DecompressedTooLarge { len: 4096, max: 1024 }));
+    }
+
+    #[test]
+    fn encode_rejects_oversized_payload() {
+        let mut codec = MsgCodec::<ClientOpcode>::new(2);
+        let mut buf = BytesMut::new();
+        let err = codec
+            .encode(MessageFrame::new(ClientOpcode::Handshake, vec![0; 3]), &mut buf)
+            .unwrap_err();
+        assert!(matches!(err, FrameError::PayloadTooLarge { len: 3, max: 2 }));
+    }
+}
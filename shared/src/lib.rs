@@ -1,8 +1,13 @@
 pub mod build;
+pub mod channel;
 pub mod client_messages;
 pub mod collision;
 pub mod core;
 pub mod macros;
+pub mod map;
 pub mod frame;
 pub mod identifier;
+pub mod protocol;
+pub mod replication;
 pub mod server_messages;
+pub mod supervisor;
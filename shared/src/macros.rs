@@ -8,7 +8,7 @@ macro_rules! message_definitions {
             )+ $(,)?
         }
     ) => {
-        #[derive(Clone, Copy)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
         $vis enum $opcode_ident {
             $(
                 $variant = $opcode_value,
@@ -38,6 +38,14 @@ macro_rules! message_definitions {
         }
 
         impl $message_ident {
+            pub fn opcode(&self) -> $opcode_ident {
+                match self {
+                    $(
+                        Self::$variant(_) => $opcode_ident::$variant,
+                    )+
+                }
+            }
+
             pub fn deserialize(
                 opcode: $opcode_ident,
                 bytes: &[u8],
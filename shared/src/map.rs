@@ -0,0 +1,3 @@
+/// Tiles below this height are water. The client draws them as sea and the server keeps
+/// everything from walking on them.
+pub const WATER_LEVEL_HEIGHT: u32 = 50;
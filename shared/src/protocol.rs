@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+/// Wire protocol version carried in every `MessageFrame` header. Bump this whenever message
+/// layouts or opcodes change in a way older peers cannot understand.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Oldest protocol version the server still accepts during the handshake.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Optional protocol features, exchanged as a bitset during the handshake. Only features both
+/// sides advertise are enabled for a connection.
+pub mod features {
+    /// The peer accepts frames flagged with `frame::FLAG_COMPRESSED`.
+    pub const COMPRESSION: u32 = 1 << 0;
+}
+
+/// Features this build of the protocol implements.
+pub const SUPPORTED_FEATURES: u32 = features::COMPRESSION;
+
+/// Heartbeat, sent by either side. The peer answers with a `Pong` carrying the same nonce,
+/// which lets the sender measure the round trip.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Ping {
+    pub nonce: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Pong {
+    pub nonce: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum HandshakeRejection {
+    UnsupportedProtocol {
+        client: u8,
+        min: u8,
+        max: u8,
+    },
+    BuildMismatch {
+        client: String,
+        server: String,
+    },
+}
+
+impl std::fmt::Display for HandshakeRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedProtocol { client, min, max } => write!(
+                f,
+                "protocol version {client} is not supported (expected {min}..={max})"
+            ),
+            Self::BuildMismatch { client, server } => write!(
+                f,
+                "client build [{client}] does not match server build [{server}]"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub protocol_version: u8,
+    pub features: u32,
+}
+
+/// Decides whether a peer announcing `client_version`, `client_build` and `client_features` can
+/// talk to this side. Builds are only compared when both sides know their git hash; a missing
+/// hash means the binary was built outside a checkout and is trusted on the protocol version
+/// alone.
+pub fn negotiate(
+    client_version: u8,
+    client_build: Option<&str>,
+    client_features: u32,
+    server_build: Option<&str>,
+) -> Result<Negotiated, HandshakeRejection> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&client_version) {
+        return Err(HandshakeRejection::UnsupportedProtocol {
+            client: client_version,
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        });
+    }
+    if let (Some(client), Some(server)) = (client_build, server_build)
+        && client != server
+    {
+        return Err(HandshakeRejection::BuildMismatch {
+            client: client.to_string(),
+            server: server.to_string(),
+        });
+    }
+    Ok(Negotiated {
+        protocol_version: client_version,
+        features: client_features & SUPPORTED_FEATURES,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiate_accepts_matching_build() {
+        let negotiated = negotiate(PROTOCOL_VERSION, Some("abc"), u32::MAX, Some("abc")).unwrap();
+        assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.features, SUPPORTED_FEATURES);
+    }
+
+    #[test]
+    fn negotiate_rejects_unsupported_protocol() {
+        let rejection = negotiate(PROTOCOL_VERSION + 1, None, 0, None).unwrap_err();
+        assert!(matches!(rejection, HandshakeRejection::UnsupportedProtocol { .. }));
+    }
+
+    #[test]
+    fn negotiate_rejects_build_mismatch_only_when_both_known() {
+        let rejection = negotiate(PROTOCOL_VERSION, Some("abc"), 0, Some("def")).unwrap_err();
+        assert!(matches!(rejection, HandshakeRejection::BuildMismatch { .. }));
+        assert!(negotiate(PROTOCOL_VERSION, None, 0, Some("def")).is_ok());
+        assert!(negotiate(PROTOCOL_VERSION, Some("abc"), 0, None).is_ok());
+    }
+}
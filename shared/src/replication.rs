@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use crate::identifier::NetEntityIdentifier;
+
+/// Everything a client is told about an entity.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct EntityState {
+    pub x: f32,
+    pub y: f32,
+    /// Gani or prototype the entity is drawn with.
+    pub appearance: Option<String>,
+    pub animation: Option<String>,
+}
+
+/// Fields of an entity that changed since a snapshot's baseline. Unchanged fields are `None`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct EntityDelta {
+    pub id: NetEntityIdentifier,
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub appearance: Option<Option<String>>,
+    pub animation: Option<Option<String>>,
+}
+
+impl EntityState {
+    /// Fields that differ from `baseline`, or every field without one. `None` if nothing changed.
+    pub fn diff(&self, id: NetEntityIdentifier, baseline: Option<&EntityState>) -> Option<EntityDelta> {
+        fn changed<T: Clone + PartialEq>(current: &T, baseline: Option<&T>) -> Option<T> {
+            (baseline != Some(current)).then(|| current.clone())
+        }
+        let delta = EntityDelta {
+            id,
+            x: changed(&self.x, baseline.map(|baseline| &baseline.x)),
+            y: changed(&self.y, baseline.map(|baseline| &baseline.y)),
+            appearance: changed(&self.appearance, baseline.map(|baseline| &baseline.appearance)),
+            animation: changed(&self.animation, baseline.map(|baseline| &baseline.animation)),
+        };
+        let unchanged = delta.x.is_none()
+            && delta.y.is_none()
+            && delta.appearance.is_none()
+            && delta.animation.is_none();
+        (!unchanged).then_some(delta)
+    }
+
+    /// Applies the fields `delta` carries on top of this state.
+    pub fn apply(&mut self, delta: &EntityDelta) {
+        if let Some(x) = delta.x {
+            self.x = x;
+        }
+        if let Some(y) = delta.y {
+            self.y = y;
+        }
+        if let Some(appearance) = &delta.appearance {
+            self.appearance = appearance.clone();
+        }
+        if let Some(animation) = &delta.animation {
+            self.animation = animation.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn state(x: f32, animation: &str) -> EntityState {
+        EntityState { x, y: 4.0, appearance: Some("bomy".to_string()), animation: Some(animation.to_string()) }
+    }
+
+    #[test]
+    fn diff_carries_only_changed_fields() {
+        let id = NetEntityIdentifier::Npc(Uuid::new_v4());
+        let baseline = state(1.0, "idle");
+        assert_eq!(baseline.diff(id, Some(&baseline)), None);
+
+        let current = state(2.0, "walk");
+        let delta = current.diff(id, Some(&baseline)).unwrap();
+        assert_eq!((delta.x, delta.y, delta.appearance.as_ref()), (Some(2.0), None, None));
+
+        let mut applied = baseline.clone();
+        applied.apply(&delta);
+        assert_eq!(applied, current);
+
+        // Without a baseline every field is sent.
+        let mut applied = EntityState { x: 0.0, y: 0.0, appearance: None, animation: None };
+        applied.apply(&current.diff(id, None).unwrap());
+        assert_eq!(applied, current);
+    }
+}
@@ -1,12 +1,113 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{identifier::NetEntityIdentifier, protocol::{HandshakeRejection, Ping, Pong}, replication::{EntityDelta, EntityState}};
+
 crate::message_definitions! {
     pub enum FromServer {
         opcode => ServerOpcode;
         Handshake(Handshake) = 0x8000;
+        LoginResult(LoginResult) = 0x8001;
+        MoveAck(MoveAck) = 0x8002;
+        EntitySpawned(EntitySpawned) = 0x8003;
+        EntityDespawned(EntityDespawned) = 0x8004;
+        Snapshot(Snapshot) = 0x8005;
+        Ping(Ping) = 0x8006;
+        Pong(Pong) = 0x8007;
+        Disconnect(DisconnectReason) = 0x8008;
+        ShutdownNotice(ShutdownNotice) = 0x8009;
     }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct Handshake {
+pub enum Handshake {
+    Accepted {
+        protocol_version: u8,
+        features: u32,
+    },
+    Rejected(HandshakeRejection),
+}
+
+/// Answer to `Login` and `Register`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum LoginResult {
+    Success,
+    InvalidCredentials,
+    AccountExists,
+    InvalidAccountName,
+    InvalidPassword,
+    AlreadyLoggedIn,
+    Throttled {
+        retry_after_secs: u32,
+    },
+    Banned {
+        reason: String,
+        /// Unix time in seconds the ban lifts at, `None` if it is permanent.
+        expires_at: Option<u64>,
+    },
+    ServerError,
+}
+
+/// Latest `MoveInput` the server applied, and where it left the player. Clients replay their
+/// newer inputs on top of this position.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct MoveAck {
+    pub sequence: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+/// An entity the client should start tracking. Always sent before the first `Snapshot`
+/// mentioning it.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct EntitySpawned {
+    pub id: NetEntityIdentifier,
+    pub state: EntityState,
+}
+
+/// An entity the client should forget.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct EntityDespawned {
+    pub id: NetEntityIdentifier,
+}
+
+/// Entity changes since `baseline`, the last snapshot the client acknowledged. Entities left out
+/// are unchanged since then, and entities it did not know yet carry every field. Without a
+/// baseline every entity is sent in full.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Snapshot {
+    pub tick: u32,
+    pub baseline: Option<u32>,
+    pub entities: Vec<EntityDelta>,
+}
+
+/// Why the server is about to close the connection. Always the last message on it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum DisconnectReason {
+    /// Removed by the server or an admin.
+    Kicked {
+        message: String,
+    },
+    Banned {
+        reason: String,
+        /// Unix time in seconds the ban lifts at, `None` if it is permanent.
+        expires_at: Option<u64>,
+    },
+    /// The server is at its connection limit.
+    ServerFull,
+    /// Too many connections from the same address.
+    TooManyConnections,
+    /// Sent messages too fast, or ones that could not be decoded.
+    Abuse,
+    /// Sent nothing, not even a heartbeat, for too long.
+    Idle,
+    /// The server is shutting down.
+    ShuttingDown,
+    /// The server lost the player's session to an internal error. Logging in again is safe.
+    SessionLost,
+}
+
+/// The server is shutting down, and will disconnect everyone in `seconds_remaining`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ShutdownNotice {
+    pub seconds_remaining: u32,
 }
@@ -0,0 +1,280 @@
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    channel::{self, OverflowPolicy, Receiver, Sender},
+    core::Core,
+};
+
+/// One-for-one restart strategy: a crashed core is restarted on its own, up to `max_restarts`
+/// times within any `window`. Past that the supervisor gives up on it.
+#[derive(Debug, Clone, Copy)]
+pub struct RestartStrategy {
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for RestartStrategy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// The core panicked and a fresh instance took over its channels.
+    Restarted {
+        core: &'static str,
+        restarts: usize,
+    },
+    /// The core panicked too often and was not restarted.
+    GaveUp {
+        core: &'static str,
+    },
+    /// The core's task returned on its own, for example after a stop command.
+    Exited {
+        core: &'static str,
+    },
+}
+
+enum Outcome {
+    Exited,
+    Panicked,
+}
+
+pub struct Supervisor {
+    strategy: RestartStrategy,
+    events_tx: Sender<SupervisorEvent>,
+    events_rx: Option<Receiver<SupervisorEvent>>,
+}
+
+impl Supervisor {
+    pub fn new(strategy: RestartStrategy) -> Self {
+        let (events_tx, events_rx) = channel::unbounded_channel();
+        Self {
+            strategy,
+            events_tx,
+            events_rx: Some(events_rx),
+        }
+    }
+
+    /// Lifecycle events of every supervised core.
+    pub fn take_events(&mut self) -> Option<Receiver<SupervisorEvent>> {
+        self.events_rx.take()
+    }
+
+    /// Starts the core built by `factory` and keeps it running. The returned `Core` keeps the
+    /// same command and event channels across restarts; commands still queued inside a core
+    /// when it crashes are lost.
+    ///
+    /// Panics if the core's command channel uses `OverflowPolicy::DisconnectSender`: the
+    /// supervisor's pump is the only sender the core sees, so one overflow would cut every caller
+    /// off for good.
+    pub fn supervise<Command, Event>(
+        &self,
+        name: &'static str,
+        mut factory: impl FnMut() -> Core<Command, Event> + Send + 'static,
+    ) -> Core<Command, Event>
+    where
+        Command: Send + 'static,
+        Event: Send + 'static,
+    {
+        let mut core = factory();
+        assert!(
+            core.tx.config().policy != OverflowPolicy::DisconnectSender,
+            "core [{name}] cannot be supervised with a DisconnectSender command channel"
+        );
+        let (outer_tx, mut outer_rx) = channel::channel(core.tx.config());
+        let outer_events = core.rx.as_ref().map(|rx| channel::channel(rx.config()));
+        let (outer_event_tx, outer_event_rx) = match outer_events {
+            Some((tx, rx)) => (Some(tx), Some(rx)),
+            None => (None, None),
+        };
+        let strategy = self.strategy;
+        let events_tx = self.events_tx.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut restarts: VecDeque<Instant> = VecDeque::new();
+            loop {
+                let (handle, tx, rx) = core.into_parts();
+                let (outcome, commands_closed) =
+                    Supervisor::run(handle, tx, rx, &mut outer_rx, outer_event_tx.as_ref()).await;
+                match outcome {
+                    Outcome::Panicked if !commands_closed => {
+                        let now = Instant::now();
+                        while restarts
+                            .front()
+                            .is_some_and(|restart| now.duration_since(*restart) > strategy.window)
+                        {
+                            restarts.pop_front();
+                        }
+                        if restarts.len() >= strategy.max_restarts {
+                            let _ = events_tx.force_send(SupervisorEvent::GaveUp { core: name });
+                            break;
+                        }
+                        restarts.push_back(now);
+                        core = factory();
+                        let _ = events_tx.force_send(SupervisorEvent::Restarted {
+                            core: name,
+                            restarts: restarts.len(),
+                        });
+                    }
+                    _ => {
+                        let _ = events_tx.force_send(SupervisorEvent::Exited { core: name });
+                        break;
+                    }
+                }
+            }
+        });
+
+        let supervised = Core::new(outer_tx, handle);
+        match outer_event_rx {
+            Some(rx) => supervised.with_events(rx),
+            None => supervised,
+        }
+    }
+
+    /// Pumps commands and events between the stable outer channels and one incarnation of a
+    /// core until its task ends. Also reports whether every outer command sender is gone.
+    async fn run<Command, Event>(
+        mut handle: JoinHandle<()>,
+        tx: Sender<Command>,
+        mut rx: Option<Receiver<Event>>,
+        outer_rx: &mut Receiver<Command>,
+        outer_event_tx: Option<&Sender<Event>>,
+    ) -> (Outcome, bool) {
+        let mut tx = Some(tx);
+        let mut commands_closed = false;
+        let result = loop {
+            tokio::select! {
+                result = &mut handle => break result,
+                command = outer_rx.recv(), if tx.is_some() => match command {
+                    Some(command) => {
+                        if let Some(inner) = &tx {
+                            // A full queue must not keep the pump from noticing a crash.
+                            tokio::select! {
+                                result = &mut handle => break result,
+                                _ = inner.send(command) => {}
+                            }
+                        }
+                    }
+                    None => {
+                        // Nobody can command the core anymore; dropping our sender lets it
+                        // wind down the same way an unsupervised core would.
+                        commands_closed = true;
+                        tx = None;
+                    }
+                },
+                event = async { rx.as_mut().unwrap().recv().await }, if rx.is_some() => match event {
+                    Some(event) => {
+                        if let Some(outer) = outer_event_tx {
+                            let _ = outer.send(event).await;
+                        }
+                    }
+                    None => rx = None,
+                },
+            }
+        };
+        // Forward whatever the core managed to emit before it went away.
+        if let (Some(rx), Some(outer)) = (rx.as_mut(), outer_event_tx) {
+            while let Some(event) = rx.try_recv() {
+                let _ = outer.send(event).await;
+            }
+        }
+        match result {
+            Err(err) if err.is_panic() => (Outcome::Panicked, commands_closed),
+            _ => (Outcome::Exited, commands_closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    use super::*;
+    use crate::{channel::ChannelConfig, core::Reply};
+
+    enum Command {
+        Stop,
+        Crash,
+        Echo { value: u32, reply: Reply<u32> },
+    }
+
+    fn start_echo_core(starts: Arc<AtomicUsize>) -> Core<Command, u32> {
+        starts.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = channel::unbounded_channel();
+        let (event_tx, event_rx) = channel::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                match command {
+                    Command::Stop => break,
+                    Command::Crash => panic!("crash requested"),
+                    Command::Echo { value, reply } => {
+                        let _ = event_tx.try_send(value);
+                        reply.send(value);
+                    }
+                }
+            }
+        });
+        Core::new(tx, handle).with_events(event_rx)
+    }
+
+    #[tokio::test]
+    async fn restarts_crashed_core_behind_the_same_channels() {
+        let mut supervisor = Supervisor::new(RestartStrategy::default());
+        let mut events = supervisor.take_events().unwrap();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let factory_starts = starts.clone();
+        let mut core = supervisor.supervise("echo", move || start_echo_core(factory_starts.clone()));
+        let mut core_events = core.take_rx().unwrap();
+
+        core.tx.send(Command::Crash).await.unwrap();
+        assert_eq!(
+            events.recv().await,
+            Some(SupervisorEvent::Restarted { core: "echo", restarts: 1 })
+        );
+        let value = core
+            .request(|reply| Command::Echo { value: 7, reply }, Duration::from_secs(1))
+            .await;
+        assert_eq!(value, Ok(7));
+        assert_eq!(core_events.recv().await, Some(7));
+        assert_eq!(starts.load(Ordering::Relaxed), 2);
+
+        core.stop(Some(Command::Stop)).await.unwrap();
+        assert_eq!(events.recv().await, Some(SupervisorEvent::Exited { core: "echo" }));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_restart_limit() {
+        let mut supervisor = Supervisor::new(RestartStrategy {
+            max_restarts: 1,
+            window: Duration::from_secs(60),
+        });
+        let mut events = supervisor.take_events().unwrap();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let factory_starts = starts.clone();
+        let core = supervisor.supervise("echo", move || start_echo_core(factory_starts.clone()));
+
+        core.tx.send(Command::Crash).await.unwrap();
+        assert!(matches!(events.recv().await, Some(SupervisorEvent::Restarted { .. })));
+        core.tx.send(Command::Crash).await.unwrap();
+        assert_eq!(events.recv().await, Some(SupervisorEvent::GaveUp { core: "echo" }));
+        assert!(core.handle.await.is_ok());
+        assert_eq!(starts.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "DisconnectSender")]
+    async fn rejects_disconnecting_command_channels() {
+        let supervisor = Supervisor::new(RestartStrategy::default());
+        supervisor.supervise("echo", || {
+            let (tx, _rx) = channel::channel::<Command>(ChannelConfig::bounded(1, OverflowPolicy::DisconnectSender));
+            Core::<Command, u32>::new(tx, tokio::spawn(async {}))
+        });
+    }
+}